@@ -0,0 +1,347 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use byteorder::{WriteBytesExt, LE};
+use ffmpeg_sys::AVSampleFormat::*;
+use ffmpeg_sys::*;
+
+use crate::error::{Error, Result};
+use crate::{clpi, fcache, ffmpeg_stuff, predict, segment::Segment};
+
+//vapoursynth api3 has no audio nodes, so the aligned audio gets written to a wav
+
+#[derive(Clone, Copy, PartialEq)]
+enum SampleKind {
+    U8,
+    S16,
+    S32,
+    F32,
+    //written as F32
+    F64,
+}
+
+impl SampleKind {
+    fn from_raw(raw: i32) -> Option<(SampleKind, bool)> {
+        let fmts = [
+            (AV_SAMPLE_FMT_U8, SampleKind::U8, false),
+            (AV_SAMPLE_FMT_S16, SampleKind::S16, false),
+            (AV_SAMPLE_FMT_S32, SampleKind::S32, false),
+            (AV_SAMPLE_FMT_FLT, SampleKind::F32, false),
+            (AV_SAMPLE_FMT_DBL, SampleKind::F64, false),
+            (AV_SAMPLE_FMT_U8P, SampleKind::U8, true),
+            (AV_SAMPLE_FMT_S16P, SampleKind::S16, true),
+            (AV_SAMPLE_FMT_S32P, SampleKind::S32, true),
+            (AV_SAMPLE_FMT_FLTP, SampleKind::F32, true),
+            (AV_SAMPLE_FMT_DBLP, SampleKind::F64, true),
+        ];
+        fmts.iter().find(|e| e.0 as i32 == raw).map(|e| (e.1, e.2))
+    }
+
+    fn in_bytes(self) -> usize {
+        match self {
+            SampleKind::U8 => 1,
+            SampleKind::S16 => 2,
+            SampleKind::S32 | SampleKind::F32 => 4,
+            SampleKind::F64 => 8,
+        }
+    }
+
+    fn out_bytes(self) -> usize {
+        match self {
+            SampleKind::F64 => 4,
+            e => e.in_bytes(),
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, SampleKind::F32 | SampleKind::F64)
+    }
+}
+
+struct WavWriter {
+    file: BufWriter<File>,
+    kind: Option<SampleKind>,
+    channels: u16,
+    sample_rate: u32,
+    //sample frames (one sample per channel)
+    written: u64,
+}
+
+impl WavWriter {
+    fn new(path: &Path) -> Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        //header gets filled in by finish
+        file.write_all(&[0u8; 44])?;
+        Ok(WavWriter {
+            file,
+            kind: None,
+            channels: 0,
+            sample_rate: 0,
+            written: 0,
+        })
+    }
+
+    fn set_format(&mut self, kind: SampleKind, channels: u16, sample_rate: u32) -> Result<()> {
+        match self.kind {
+            None => {
+                self.kind = Some(kind);
+                self.channels = channels;
+                self.sample_rate = sample_rate;
+                Ok(())
+            }
+            Some(k)
+                if k == kind && self.channels == channels && self.sample_rate == sample_rate =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::Audio("Audio format changes midstream".to_owned())),
+        }
+    }
+
+    fn frame_bytes(&self) -> usize {
+        self.kind.map(|e| e.out_bytes()).unwrap_or(0) * self.channels as usize
+    }
+
+    fn write_silence(&mut self, samples: u64) -> Result<()> {
+        let silence = match self.kind {
+            Some(SampleKind::U8) => 0x80,
+            _ => 0,
+        };
+        let row = vec![silence; self.frame_bytes()];
+        for _ in 0..samples {
+            self.file.write_all(&row)?;
+        }
+        self.written += samples;
+        Ok(())
+    }
+
+    //writes samples [from, to) of the frame interleaved
+    unsafe fn write_frame(
+        &mut self,
+        frame: &AVFrame,
+        planar: bool,
+        from: usize,
+        to: usize,
+    ) -> Result<()> {
+        let kind = self.kind.unwrap();
+        let in_bytes = kind.in_bytes();
+        let channels = self.channels as usize;
+
+        for i in from..to {
+            for c in 0..channels {
+                let src = if planar {
+                    (*frame.extended_data.add(c)).add(i * in_bytes)
+                } else {
+                    (*frame.extended_data).add((i * channels + c) * in_bytes)
+                };
+                let src = std::slice::from_raw_parts(src, in_bytes);
+                if kind == SampleKind::F64 {
+                    let v = f64::from_ne_bytes(src.try_into().unwrap());
+                    self.file.write_f32::<LE>(v as f32)?;
+                } else {
+                    self.file.write_all(src)?;
+                }
+            }
+        }
+        self.written += (to - from) as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let kind = match self.kind {
+            Some(e) => e,
+            None => return Err(Error::Audio("No audio decoded".to_owned())),
+        };
+        let data_size = self.written * self.frame_bytes() as u64;
+        if data_size > u32::MAX as u64 - 36 {
+            return Err(Error::Audio("Audio too large for a wav file".to_owned()));
+        }
+        let bits = kind.out_bytes() as u16 * 8;
+        let block_align = self.channels * kind.out_bytes() as u16;
+
+        let f = &mut self.file;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(b"RIFF")?;
+        f.write_u32::<LE>(36 + data_size as u32)?;
+        f.write_all(b"WAVE")?;
+        f.write_all(b"fmt ")?;
+        f.write_u32::<LE>(16)?;
+        f.write_u16::<LE>(if kind.is_float() { 3 } else { 1 })?;
+        f.write_u16::<LE>(self.channels)?;
+        f.write_u32::<LE>(self.sample_rate)?;
+        f.write_u32::<LE>(self.sample_rate * block_align as u32)?;
+        f.write_u16::<LE>(block_align)?;
+        f.write_u16::<LE>(bits)?;
+        f.write_all(b"data")?;
+        f.write_u32::<LE>(data_size as u32)?;
+        f.flush()?;
+        Ok(())
+    }
+}
+
+//signed difference of two 90khz timestamps with 33 bit wraparound
+fn pts_diff(a: i64, b: i64) -> i64 {
+    let d = (a - b).rem_euclid(1 << 33);
+    if d >= 1 << 32 {
+        d - (1 << 33)
+    } else {
+        d
+    }
+}
+
+//first audio stream of the clip
+pub fn default_audio_pid(clpi: &clpi::CLPIResult) -> Option<u16> {
+    clpi.programs
+        .iter()
+        .flat_map(|e| e.streams.iter())
+        .find(|e| clpi::is_audio_coding_type(e.coding_type))
+        .map(|e| e.pid)
+}
+
+//audio samples before output frame `frame`
+fn samples_before(frame: u64, sample_rate: u32, frame_rate: (u32, u32)) -> u64 {
+    ((frame as f64 * sample_rate as f64 * frame_rate.1 as f64) / frame_rate.0 as f64).round() as u64
+}
+
+//decodes the audio belonging to output frames [first, last] of a segment and appends it
+unsafe fn dump_segment(
+    seg: &Segment,
+    audio_pid: u16,
+    first: u64,
+    last: u64,
+    out: &mut WavWriter,
+    frame_rate: (u32, u32),
+) -> Result<()> {
+    let coding_type = match seg.clpi.program_stream(audio_pid) {
+        Some(e) => e.coding_type,
+        None => {
+            return Err(Error::Audio(format!(
+                "Stream pid {} not in the clip",
+                audio_pid
+            )))
+        }
+    };
+    let codec_id = match ffmpeg_stuff::codec_for_coding_type(coding_type) {
+        Some(e) if clpi::is_audio_coding_type(coding_type) => e,
+        _ => {
+            return Err(Error::Audio(format!(
+                "Stream pid {} is not a supported audio stream",
+                audio_pid
+            )))
+        }
+    };
+
+    let local_first = first - seg.start + seg.in_frame;
+    let start_pts = seg.frame_to_pts(local_first) as i64 * 2;
+
+    //audio is muxed ahead of the video, so start a gop early
+    let (gop, _) = predict::get_frame_dump_info(local_first, &seg.pred)?;
+    let gop = gop.saturating_sub(1);
+
+    let mut setup = ffmpeg_stuff::setup_decoder(
+        fcache::FCached::new(seg.backend.clone()),
+        seg.pred[gop].spn,
+        audio_pid,
+        codec_id,
+    )?;
+
+    let mut seg_samples: Option<(u64, u64)> = None;
+    let mut seg_written = 0u64;
+
+    let frame = ffmpeg_stuff::AutoFreeFrame {
+        frame: av_frame_alloc(),
+    };
+    //the decoder gets drained at the end of the stream
+    while ffmpeg_stuff::receive_frame(&mut setup, frame.frame)? {
+        let f = &*frame.frame;
+        let (kind, planar) = match SampleKind::from_raw(f.format) {
+            Some(e) => e,
+            None => {
+                return Err(Error::Audio(format!(
+                    "Unsupported sample format {}",
+                    f.format
+                )))
+            }
+        };
+        out.set_format(kind, f.channels as u16, f.sample_rate as u32)?;
+
+        //sample range of this segment in the output
+        let (seg_start, seg_end) = *seg_samples.get_or_insert_with(|| {
+            (
+                samples_before(first, out.sample_rate, frame_rate),
+                samples_before(last + 1, out.sample_rate, frame_rate),
+            )
+        });
+        let seg_len = seg_end - seg_start;
+
+        let pts = if f.best_effort_timestamp != AV_NOPTS_VALUE {
+            f.best_effort_timestamp
+        } else {
+            f.pts
+        };
+        if pts == AV_NOPTS_VALUE {
+            continue;
+        }
+        //packets are timestamped in 90khz
+        let offset = pts_diff(pts, start_pts);
+        let frame_start = (offset as f64 * out.sample_rate as f64 / 90_000.0).round() as i64;
+
+        if frame_start >= seg_len as i64 {
+            break;
+        }
+
+        //gaps get filled with silence, overlaps skipped
+        if frame_start > seg_written as i64 {
+            let gap = frame_start as u64 - seg_written;
+            out.write_silence(gap)?;
+            seg_written += gap;
+        }
+        let nb = f.nb_samples as i64;
+        let from = (seg_written as i64 - frame_start).clamp(0, nb);
+        let to = (seg_len as i64 - frame_start).clamp(0, nb);
+        if from < to {
+            out.write_frame(f, planar, from as usize, to as usize)?;
+            seg_written += (to - from) as u64;
+        }
+    }
+
+    if let Some((seg_start, seg_end)) = seg_samples {
+        //keep the next segment aligned even if the audio ends early
+        let seg_len = seg_end - seg_start;
+        if seg_written < seg_len {
+            out.write_silence(seg_len - seg_written)?;
+        }
+    }
+    Ok(())
+}
+
+//writes the audio of output frames [first, last] to a wav, sample aligned to the video frame numbering
+pub fn dump_audio(
+    segments: &[Segment],
+    audio_pid: Option<u16>,
+    first: u64,
+    last: u64,
+    output: &Path,
+) -> Result<()> {
+    let audio_pid = match audio_pid.or_else(|| default_audio_pid(&segments[0].clpi)) {
+        Some(e) => e,
+        None => return Err(Error::Audio("No audio stream".to_owned())),
+    };
+
+    let mut out = WavWriter::new(output)?;
+    for seg in segments {
+        let seg_first = first.max(seg.start);
+        let seg_last = last.min(seg.start + seg.num_frames - 1);
+        if seg_first > seg_last {
+            continue;
+        }
+        let frame_rate = (seg.frame_rate_n, seg.frame_rate_d);
+        unsafe {
+            dump_segment(seg, audio_pid, seg_first, seg_last, &mut out, frame_rate)?;
+        }
+    }
+    out.finish()
+}
@@ -2,28 +2,103 @@ use std::sync::*;
 
 use crate::ffmpeg_stuff::DecoderSetup;
 
-#[derive(Clone)]
 pub struct CachedDecoder {
-    pub decoder: Option<Arc<Mutex<DecoderSetup>>>,
-    pub start_idx: u64,
+    pub decoder: DecoderSetup,
+    //output frame number of the next decoded frame
     pub current_idx: u64,
+    //until the end of its gop
     pub frames_left: u64,
-    pub frames_to_serve_total: u64,
 }
 
+//the ffmpeg context is only touched by the thread holding the decoder's lock
+unsafe impl Send for CachedDecoder {}
+
 impl CachedDecoder {
-    pub fn new(
-        decoder: DecoderSetup,
-        start_idx: u64,
-        current_idx: u64,
-        frames_to_serve: u64,
-    ) -> CachedDecoder {
+    pub fn new(decoder: DecoderSetup, current_idx: u64, frames_to_serve: u64) -> CachedDecoder {
         CachedDecoder {
-            decoder: Some(Arc::new(Mutex::new(decoder))),
-            start_idx,
+            decoder,
             current_idx,
             frames_left: frames_to_serve,
-            frames_to_serve_total: frames_to_serve,
         }
     }
+
+    pub fn can_serve(&self, n: u64) -> bool {
+        n >= self.current_idx && n < self.current_idx + self.frames_left
+    }
+}
+
+//copy of the decoder position so it can be chosen without waiting for its lock
+struct PoolEntry {
+    decoder: Arc<Mutex<CachedDecoder>>,
+    current_idx: u64,
+    frames_left: u64,
+    last_used: u64,
+}
+
+//decoders positioned in different gops, each one is only used by one thread at a time
+pub struct DecoderPool {
+    entries: Mutex<(Vec<PoolEntry>, u64)>,
+    size: usize,
+}
+
+impl DecoderPool {
+    pub fn new(size: usize) -> DecoderPool {
+        DecoderPool {
+            entries: Mutex::new((Vec::new(), 0)),
+            size: size.max(1),
+        }
+    }
+
+    //the decoder closest before `n` that reaches it by decoding forward,
+    //it may be in use and has to be checked again once locked
+    pub fn find(&self, n: u64) -> Option<Arc<Mutex<CachedDecoder>>> {
+        let lck = self.entries.lock().unwrap();
+        lck.0
+            .iter()
+            .filter(|e| n >= e.current_idx && n < e.current_idx + e.frames_left)
+            .max_by_key(|e| e.current_idx)
+            .map(|e| e.decoder.clone())
+    }
+
+    //drops the least recently used decoder when full
+    pub fn insert(&self, decoder: CachedDecoder) {
+        if decoder.frames_left == 0 {
+            return;
+        }
+        let mut lck = self.entries.lock().unwrap();
+        if lck.0.len() >= self.size {
+            let oldest = (0..lck.0.len())
+                .min_by_key(|e| lck.0[*e].last_used)
+                .unwrap();
+            lck.0.swap_remove(oldest);
+        }
+        lck.1 += 1;
+        let entry = PoolEntry {
+            current_idx: decoder.current_idx,
+            frames_left: decoder.frames_left,
+            decoder: Arc::new(Mutex::new(decoder)),
+            last_used: lck.1,
+        };
+        lck.0.push(entry);
+    }
+
+    //called with `decoder` still locked after it was used, drops it at the end of its gop
+    pub fn update(&self, decoder: &Arc<Mutex<CachedDecoder>>, state: &CachedDecoder) {
+        let mut lck = self.entries.lock().unwrap();
+        lck.1 += 1;
+        let tick = lck.1;
+        let idx = match lck.0.iter().position(|e| Arc::ptr_eq(&e.decoder, decoder)) {
+            Some(e) => e,
+            //already evicted
+            None => return,
+        };
+        if state.frames_left == 0 {
+            lck.0.swap_remove(idx);
+            return;
+        }
+        let entry = &mut lck.0[idx];
+        entry.current_idx = state.current_idx;
+        entry.frames_left = state.frames_left;
+        entry.last_used = tick;
+    }
 }
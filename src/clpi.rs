@@ -1,21 +1,17 @@
-use std::{
-    error::Error,
-    fs::File,
-    io::{Cursor, Read, Seek, SeekFrom},
-};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 
 use byteorder::{ReadBytesExt, BE};
 
-struct Coarse {
-    fine_id: u32,
-    pts: u16,
-    spn: u32,
+use crate::error::{Error, Result};
+
+pub struct Coarse {
+    pub fine_id: u32,
+    pub pts: u16,
+    pub spn: u32,
 }
-struct Fine {
-    //reserved: u8,
-    //endpos: u8,
-    pts: u16,
-    spn: u32,
+pub struct Fine {
+    pub pts: u16,
+    pub spn: u32,
 }
 
 pub struct Combined {
@@ -23,141 +19,452 @@ pub struct Combined {
     pub spn: u32,
 }
 
-pub struct CLPIResult {
-    pub stream_pid: u16,
+pub struct StcSequence {
+    pub id: u8,
+    pub spn_stc_start: u32,
+    //45khz
+    pub presentation_start_time: u32,
+    pub presentation_end_time: u32,
+}
+
+pub struct AtcSequence {
+    pub stc_sequences: Vec<StcSequence>,
+}
+
+pub struct ProgramStream {
+    pub pid: u16,
+    pub coding_type: u8,
+    //only set for video streams
+    pub video_format: u8,
+    pub frame_rate: u8,
+}
+
+pub struct Program {
+    pub streams: Vec<ProgramStream>,
+}
+
+#[derive(Default)]
+pub struct ClipInfo {
+    pub type_indicator: String,
+    pub version: String,
+    pub clip_stream_type: u8,
+    pub application_type: u8,
+    pub is_atc_delta: bool,
+    pub ts_recording_rate: u32,
+    pub num_source_packets: u32,
+    pub format_identifier: String,
+}
+
+pub struct ClipMark {
+    pub mark_type: u8,
+    pub stc_id: u16,
+    pub time_stamp: u32,
+    pub entry_es_pid: u16,
+    pub duration: u32,
+}
+
+pub struct ExtDataEntry {
+    pub id1: u16,
+    pub id2: u16,
+    pub start_address: u32,
+    pub length: u32,
+}
+
+pub struct EpMapStream {
+    pub pid: u16,
+    pub stream_type: u8,
     pub combined: Vec<Combined>,
 }
 
-fn parse_cpi(buf: &[u8]) -> Result<CLPIResult, Box<dyn Error>> {
-    let stream_pid;
+pub struct CLPIResult {
+    pub clip_info: ClipInfo,
+    pub clip_marks: Vec<ClipMark>,
+    pub extension_data: Vec<ExtDataEntry>,
+
+    pub ep_streams: Vec<EpMapStream>,
+
+    pub atc_sequences: Vec<AtcSequence>,
+    pub programs: Vec<Program>,
+}
+
+impl CLPIResult {
+    //pid None picks the first EP map stream
+    pub fn ep_stream(&self, pid: Option<u16>) -> Result<&EpMapStream> {
+        let found = match pid {
+            Some(pid) => self.ep_streams.iter().find(|e| e.pid == pid),
+            None => self.ep_streams.first(),
+        };
+        match found {
+            Some(e) => Ok(e),
+            None => Err(Error::NoEpMap {
+                pid,
+                available: self.ep_streams.iter().map(|e| e.pid).collect(),
+            }),
+        }
+    }
+
+    pub fn program_stream(&self, pid: u16) -> Option<&ProgramStream> {
+        self.programs
+            .iter()
+            .flat_map(|e| e.streams.iter())
+            .find(|e| e.pid == pid)
+    }
+
+    //the rate code as written, it can be the field rate of interlaced streams
+    pub fn frame_rate(&self, pid: u16) -> Option<(u32, u32)> {
+        let stream = self.program_stream(pid)?;
+        frame_rate_from_code(stream.frame_rate)
+    }
+
+    pub fn stc_sequences(&self) -> Vec<&StcSequence> {
+        self.atc_sequences
+            .iter()
+            .flat_map(|e| e.stc_sequences.iter())
+            .collect()
+    }
+}
+
+pub fn frame_rate_from_code(code: u8) -> Option<(u32, u32)> {
+    match code {
+        1 => Some((24000, 1001)),
+        2 => Some((24, 1)),
+        3 => Some((25, 1)),
+        4 => Some((30000, 1001)),
+        6 => Some((50, 1)),
+        7 => Some((60000, 1001)),
+        _ => None,
+    }
+}
+
+fn is_video_coding_type(coding_type: u8) -> bool {
+    matches!(coding_type, 0x01 | 0x02 | 0x1B | 0x20 | 0x24 | 0xEA)
+}
+
+pub fn is_audio_coding_type(coding_type: u8) -> bool {
+    matches!(coding_type, 0x80..=0x86 | 0xA1 | 0xA2)
+}
+
+fn read_string(b: &mut impl Read, len: usize) -> Result<String> {
+    let mut buf = vec![0u8; len];
+    b.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn parse_clip_info(buf: &[u8]) -> Result<ClipInfo> {
     let mut b = Cursor::new(buf);
 
-    //junk
-    b.read(&mut [0u8; 3])?;
+    let _reserved = b.read_u16::<BE>()?;
+    let clip_stream_type = b.read_u8()?;
+    let application_type = b.read_u8()?;
+    let is_atc_delta = b.read_u32::<BE>()? & 1 != 0;
+    let ts_recording_rate = b.read_u32::<BE>()?;
+    let num_source_packets = b.read_u32::<BE>()?;
+    b.seek(SeekFrom::Current(128))?; //reserved
 
-    let num_stream_pid = b.read_u8()?;
+    //TS_type_info_block
+    let _ts_type_info_length = b.read_u16::<BE>()?;
+    let _validity_flags = b.read_u8()?;
+    let format_identifier = read_string(&mut b, 4)?;
 
-    //println!("num_stream_pid: {}", num_stream_pid);
+    Ok(ClipInfo {
+        clip_stream_type,
+        application_type,
+        is_atc_delta,
+        ts_recording_rate,
+        num_source_packets,
+        format_identifier,
+        ..Default::default()
+    })
+}
 
-    for _ in 0..num_stream_pid {
-        let mut blob = [0u8; 12];
-        b.read(&mut blob)?;
-        let mut bread = bitreader::BitReader::new(&blob);
-        stream_pid = bread.read_u16(16)?;
-        bread.skip(10)?; //Resredev
-        let _stream_type = bread.read_u8(4)?;
-        let coarse_entries = bread.read_u16(16)?;
-        let fine_entries = bread.read_u32(18)?;
-        let epmap_addr = bread.read_u32(32)?;
-
-        //println!("  stream_pid: {}", stream_pid);
-        //println!("  stream_type: {}", stream_type);
-        //println!("  coarse_entries: {}", coarse_entries);
-        //println!("  fine_entries: {}", fine_entries);
-        //println!("  epmap_addr: {}", epmap_addr);
-
-        let real_addr = epmap_addr + 2; // start right after type
-        b.seek(SeekFrom::Start(real_addr as _))?;
-        let fine_table_addr = b.read_u32::<BE>()?;
-        //println!("fine: {}", fine_table_addr);
-
-        let mut coarse = Vec::new();
-        let mut fines = Vec::new();
-
-        coarse.reserve(coarse_entries as usize);
-        fines.reserve(fine_entries as usize);
-        for xx in 0..coarse_entries {
-            let mut blob = [0u8; 8];
-            b.read(&mut blob)?;
-            let mut bread = bitreader::BitReader::new(&blob);
-
-            let fine_id_ref = bread.read_u32(18)?;
-            let pts = bread.read_u16(14)?;
-            let spn = bread.read_u32(32)?;
-            //  if xx < 10 || xx  > coarse_entries  -10{
-            //      println!("{} {} {}",fine_id_ref,pts,spn);
-            //  }
-            let _ = xx;
-            coarse.push(Coarse {
-                fine_id: fine_id_ref,
-                pts,
-                spn,
+fn parse_clip_mark(buf: &[u8]) -> Result<Vec<ClipMark>> {
+    let mut b = Cursor::new(buf);
+    if buf.len() < 4 {
+        return Ok(Vec::new());
+    }
+
+    let _reserved = b.read_u16::<BE>()?;
+    let num_clip_marks = b.read_u16::<BE>()?;
+
+    let mut marks = Vec::with_capacity(num_clip_marks as usize);
+    for _ in 0..num_clip_marks {
+        let _reserved = b.read_u8()?;
+        marks.push(ClipMark {
+            mark_type: b.read_u8()?,
+            stc_id: b.read_u16::<BE>()?,
+            time_stamp: b.read_u32::<BE>()?,
+            entry_es_pid: b.read_u16::<BE>()?,
+            duration: b.read_u32::<BE>()?,
+        });
+    }
+    Ok(marks)
+}
+
+//the entry addresses are relative to the start of the ExtensionData block (its length field)
+fn parse_extension_data(buf: &[u8]) -> Result<Vec<ExtDataEntry>> {
+    let mut b = Cursor::new(buf);
+    if buf.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let _data_block_start_address = b.read_u32::<BE>()?;
+    b.seek(SeekFrom::Current(3))?; //reserved
+    let num_entries = b.read_u8()?;
+
+    let mut entries = Vec::with_capacity(num_entries as usize);
+    for _ in 0..num_entries {
+        entries.push(ExtDataEntry {
+            id1: b.read_u16::<BE>()?,
+            id2: b.read_u16::<BE>()?,
+            start_address: b.read_u32::<BE>()?,
+            length: b.read_u32::<BE>()?,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse_sequence_info(buf: &[u8]) -> Result<Vec<AtcSequence>> {
+    let mut b = Cursor::new(buf);
+
+    let _reserved = b.read_u8()?;
+    let num_atc_seq = b.read_u8()?;
+
+    let mut atc_sequences = Vec::with_capacity(num_atc_seq as usize);
+    for _ in 0..num_atc_seq {
+        let _spn_atc_start = b.read_u32::<BE>()?;
+        let num_stc_seq = b.read_u8()?;
+        let offset_stc_id = b.read_u8()?;
+
+        let mut stc_sequences = Vec::with_capacity(num_stc_seq as usize);
+        for i in 0..num_stc_seq {
+            let _pcr_pid = b.read_u16::<BE>()?;
+            stc_sequences.push(StcSequence {
+                id: offset_stc_id.wrapping_add(i),
+                spn_stc_start: b.read_u32::<BE>()?,
+                presentation_start_time: b.read_u32::<BE>()?,
+                presentation_end_time: b.read_u32::<BE>()?,
             });
         }
+        atc_sequences.push(AtcSequence { stc_sequences });
+    }
+    Ok(atc_sequences)
+}
 
-        let fine_real_addr = real_addr + fine_table_addr;
-        b.seek(SeekFrom::Start(fine_real_addr as _))?;
-
-        //println!("");
-        //println!("FINE");
-        //println!("");
-        for xx in 0..fine_entries {
-            let mut blob = [0u8; 4];
-            b.read(&mut blob)?;
-            let mut bread = bitreader::BitReader::new(&blob);
-            let _reserved = bread.read_u8(1)?;
-            let _end_pos_offset = bread.read_u8(3)?;
-            let pts_fine = bread.read_u16(11)?;
-            let spn_fine = bread.read_u32(17)?;
-
-            //if xx < 10 || xx  > fine_entries  -10{
-            //    println!("{} {}",pts_fine,spn_fine);
-            //}
-            let _ = xx;
-
-            //println!("{}",spn_fine);
-            fines.push(Fine {
-                //reserved: reserved,
-                // endpos: end_pos_offset,
-                pts: pts_fine,
-                spn: spn_fine,
-            })
-        }
+fn parse_program_info(buf: &[u8]) -> Result<Vec<Program>> {
+    let mut b = Cursor::new(buf);
+
+    let _reserved = b.read_u8()?;
+    let num_programs = b.read_u8()?;
+
+    let mut programs = Vec::with_capacity(num_programs as usize);
+    for _ in 0..num_programs {
+        let _spn_program_sequence_start = b.read_u32::<BE>()?;
+        let _program_map_pid = b.read_u16::<BE>()?;
+        let num_streams = b.read_u8()?;
+        let _num_groups = b.read_u8()?;
+
+        let mut streams = Vec::with_capacity(num_streams as usize);
+        for _ in 0..num_streams {
+            let pid = b.read_u16::<BE>()?;
 
-        let mut combined = Vec::with_capacity(fine_entries as usize);
+            let coding_info_length = b.read_u8()?;
+            let coding_info_start = b.position();
+            let coding_type = b.read_u8()?;
 
-        for (ii, c) in coarse.iter().enumerate() {
-            let start = c.fine_id;
-            let end_fine = if ii != coarse_entries as usize - 1 {
-                coarse[ii + 1].fine_id
+            let (video_format, frame_rate) = if is_video_coding_type(coding_type) {
+                let fmt = b.read_u8()?;
+                (fmt >> 4, fmt & 0x0F)
             } else {
-                fine_entries
+                (0, 0)
             };
-            let coarse_spn = c.spn as u32 & (!0x1FFFF);
-            let coarse_pts = (c.pts as u64 & (!(0x01 as u64))) << 18;
+            //audio/graphics attributes are not needed
+            b.seek(SeekFrom::Start(
+                coding_info_start + coding_info_length as u64,
+            ))?;
 
-            for fine in start..end_fine {
-                let fine_entry = &fines[fine as usize];
-
-                let spn = coarse_spn + fine_entry.spn;
-                let pts = coarse_pts + ((fine_entry.pts as u64) << 8);
-                combined.push(Combined { pts, spn })
-            }
+            streams.push(ProgramStream {
+                pid,
+                coding_type,
+                video_format,
+                frame_rate,
+            });
         }
-        //for i in 0..50 {
-        //    let c = &combined[i];
-        //    println!("{} {} {}", c.spn, c.pts, c.pts as f32 / 45_000.0);
-        //}
-        //combined.sort_by(|a,b| a.spn.partial_cmp(&b.spn).unwrap());
-        return Ok(CLPIResult {
-            stream_pid,
-            combined,
+        programs.push(Program { streams });
+    }
+    Ok(programs)
+}
+
+fn parse_ep_map_stream(b: &mut Cursor<&[u8]>) -> Result<EpMapStream> {
+    let mut blob = [0u8; 12];
+    b.read_exact(&mut blob)?;
+    let mut bread = bitreader::BitReader::new(&blob);
+    let pid = bread.read_u16(16)?;
+    bread.skip(10)?; //Resredev
+    let stream_type = bread.read_u8(4)?;
+    let coarse_entries = bread.read_u16(16)?;
+    let fine_entries = bread.read_u32(18)?;
+    let epmap_addr = bread.read_u32(32)?;
+
+    //println!("  stream_pid: {}", pid);
+    //println!("  stream_type: {}", stream_type);
+    //println!("  coarse_entries: {}", coarse_entries);
+    //println!("  fine_entries: {}", fine_entries);
+    //println!("  epmap_addr: {}", epmap_addr);
+
+    //the next stream entry follows this one
+    let next_entry = b.position();
+
+    let real_addr = epmap_addr + 2; // start right after type
+    b.seek(SeekFrom::Start(real_addr as _))?;
+    let fine_table_addr = b.read_u32::<BE>()?;
+    //println!("fine: {}", fine_table_addr);
+
+    let mut coarse = Vec::with_capacity(coarse_entries as usize);
+    let mut fines = Vec::with_capacity(fine_entries as usize);
+
+    for _ in 0..coarse_entries {
+        let mut blob = [0u8; 8];
+        b.read_exact(&mut blob)?;
+        let mut bread = bitreader::BitReader::new(&blob);
+
+        let fine_id_ref = bread.read_u32(18)?;
+        let pts = bread.read_u16(14)?;
+        let spn = bread.read_u32(32)?;
+        coarse.push(Coarse {
+            fine_id: fine_id_ref,
+            pts,
+            spn,
         });
     }
-    Err(Box::new(simple_error::simple_error!("Errr")))
+
+    let fine_real_addr = real_addr + fine_table_addr;
+    b.seek(SeekFrom::Start(fine_real_addr as _))?;
+
+    for _ in 0..fine_entries {
+        let mut blob = [0u8; 4];
+        b.read_exact(&mut blob)?;
+        let mut bread = bitreader::BitReader::new(&blob);
+        let _reserved = bread.read_u8(1)?;
+        let _end_pos_offset = bread.read_u8(3)?;
+        let pts_fine = bread.read_u16(11)?;
+        let spn_fine = bread.read_u32(17)?;
+
+        fines.push(Fine {
+            pts: pts_fine,
+            spn: spn_fine,
+        })
+    }
+
+    let mut combined = Vec::with_capacity(fine_entries as usize);
+
+    for (ii, c) in coarse.iter().enumerate() {
+        let start = c.fine_id;
+        let end_fine = if ii != coarse_entries as usize - 1 {
+            coarse[ii + 1].fine_id
+        } else {
+            fine_entries
+        };
+        let coarse_spn = c.spn & !0x1FFFF;
+        let coarse_pts = (c.pts as u64 & !0x01) << 18;
+
+        for fine in start..end_fine {
+            let fine_entry = match fines.get(fine as usize) {
+                Some(e) => e,
+                None => {
+                    return Err(Error::InvalidClpi(format!(
+                        "EP map of pid {} references fine entry {} of {}",
+                        pid, fine, fine_entries
+                    )))
+                }
+            };
+
+            let spn = coarse_spn + fine_entry.spn;
+            let pts = coarse_pts + ((fine_entry.pts as u64) << 8);
+            combined.push(Combined { pts, spn })
+        }
+    }
+    //for i in 0..50 {
+    //    let c = &combined[i];
+    //    println!("{} {} {}", c.spn, c.pts, c.pts as f32 / 45_000.0);
+    //}
+
+    b.seek(SeekFrom::Start(next_entry))?;
+
+    Ok(EpMapStream {
+        pid,
+        stream_type,
+        combined,
+    })
+}
+
+fn parse_cpi(buf: &[u8]) -> Result<Vec<EpMapStream>> {
+    let mut b = Cursor::new(buf);
+
+    //junk
+    b.read_exact(&mut [0u8; 3])?;
+
+    let num_stream_pid = b.read_u8()?;
+
+    //println!("num_stream_pid: {}", num_stream_pid);
+
+    let mut ep_streams = Vec::with_capacity(num_stream_pid as usize);
+    for _ in 0..num_stream_pid {
+        let ep = parse_ep_map_stream(&mut b)?;
+        if ep.combined.is_empty() {
+            return Err(Error::InvalidClpi(format!(
+                "EP map of pid {} has no entries",
+                ep.pid
+            )));
+        }
+        ep_streams.push(ep);
+    }
+    if ep_streams.is_empty() {
+        return Err(Error::InvalidClpi(
+            "CPI contains no EP map streams".to_owned(),
+        ));
+    }
+    Ok(ep_streams)
 }
 
-pub fn read_clpi(clip_file: &mut File) -> Result<CLPIResult, Box<dyn Error>> {
+//length prefixed block, address 0 means not present
+fn read_block(clip_file: &mut (impl Read + Seek), addr: u32) -> Result<Vec<u8>> {
+    if addr == 0 {
+        return Ok(Vec::new());
+    }
+    let file_len = clip_file.seek(SeekFrom::End(0))?;
+    clip_file.seek(SeekFrom::Start(addr as u64))?;
+    let length = clip_file.read_u32::<BE>()?;
+    //don't allocate whatever a corrupted length says
+    if addr as u64 + 4 + length as u64 > file_len {
+        return Err(Error::InvalidClpi(format!(
+            "block at {} with length {} is past the end of the file ({})",
+            addr, length, file_len
+        )));
+    }
+    let mut buf = vec![0u8; length as usize];
+    clip_file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn read_clpi(clip_file: &mut (impl Read + Seek)) -> Result<CLPIResult> {
     clip_file.seek(SeekFrom::Start(0))?;
-    let mut asd = [0u8; 4];
     //TypeIndecators
-    clip_file.read(&mut asd)?;
-    clip_file.read(&mut asd)?;
+    let type_indicator = read_string(clip_file, 4)?;
+    if type_indicator != "HDMV" {
+        return Err(Error::InvalidClpi(format!(
+            "type indicator is {:?}",
+            type_indicator
+        )));
+    }
+    let version = read_string(clip_file, 4)?;
 
-    let _seq_info_addr = clip_file.read_u32::<BE>()?;
-    let _prog_info_addr = clip_file.read_u32::<BE>()?;
+    let seq_info_addr = clip_file.read_u32::<BE>()?;
+    let prog_info_addr = clip_file.read_u32::<BE>()?;
     let cpi_info_addr = clip_file.read_u32::<BE>()?;
-    let _clip_mark_info_addr = clip_file.read_u32::<BE>()?;
+    let clip_mark_info_addr = clip_file.read_u32::<BE>()?;
+    let ext_data_addr = clip_file.read_u32::<BE>()?;
+    //12 reserved bytes, ClipInfo follows at 40
+    let clip_info_addr = 40;
 
     //println!(
     //    "seq_info_addr {} prog_info_addr {}",
@@ -168,17 +475,352 @@ pub fn read_clpi(clip_file: &mut File) -> Result<CLPIResult, Box<dyn Error>> {
     //    cpi_info_addr, clip_mark_info_addr
     //);
 
-    clip_file.seek(SeekFrom::Start(cpi_info_addr as u64))?;
+    if cpi_info_addr == 0 {
+        return Err(Error::InvalidClpi("no CPI".to_owned()));
+    }
+    let ep_streams = parse_cpi(&read_block(clip_file, cpi_info_addr)?)?;
+
+    let mut clip_info = parse_clip_info(&read_block(clip_file, clip_info_addr)?)?;
+    clip_info.type_indicator = type_indicator;
+    clip_info.version = version;
 
-    let cpi_length = clip_file.read_u32::<BE>()?;
-    //println!("cpi len {}", cpi_length);
-    //println!(
-    //    "{}",
-    //    cpi_length as u64 + (clip_file.stream_position()?) as u64
-    //);
-    let mut cpi = vec![0u8; cpi_length as usize];
-    clip_file.read(&mut cpi)?;
-    //println!("Reda");
+    Ok(CLPIResult {
+        clip_info,
+        clip_marks: parse_clip_mark(&read_block(clip_file, clip_mark_info_addr)?)?,
+        extension_data: parse_extension_data(&read_block(clip_file, ext_data_addr)?)?,
+        ep_streams,
+        atc_sequences: parse_sequence_info(&read_block(clip_file, seq_info_addr)?)?,
+        programs: parse_program_info(&read_block(clip_file, prog_info_addr)?)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(pid: u16, coding_info: &[u8]) -> Vec<u8> {
+        let mut d = pid.to_be_bytes().to_vec();
+        d.push(coding_info.len() as u8);
+        d.extend(coding_info);
+        d
+    }
+
+    fn program_info(streams: &[Vec<u8>]) -> Vec<u8> {
+        let mut d = vec![0, 1];
+        d.extend(0u32.to_be_bytes());
+        d.extend(0x0100u16.to_be_bytes());
+        d.push(streams.len() as u8);
+        d.push(0);
+        d.extend(streams.concat());
+        d
+    }
+
+    #[test]
+    fn reads_program_streams() {
+        let buf = program_info(&[
+            //h264 1080i, 29.97
+            stream(0x1011, &[0x1B, 0x44, 0, 0, 0]),
+            //LPCM, the attributes get skipped
+            stream(0x1100, &[0x80, 0x31, 0, 0, 0, 0]),
+            //HEVC 2160p, 23.976
+            stream(0x1015, &[0x24, 0x81, 0, 0, 0, 0, 0, 0]),
+        ]);
+        let programs = parse_program_info(&buf).unwrap();
+        assert_eq!(programs.len(), 1);
+
+        let streams = &programs[0].streams;
+        let got: Vec<_> = streams
+            .iter()
+            .map(|s| (s.pid, s.coding_type, s.video_format, s.frame_rate))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                (0x1011, 0x1B, 4, 4),
+                (0x1100, 0x80, 0, 0),
+                (0x1015, 0x24, 8, 1)
+            ]
+        );
+    }
+
+    //spn_stc_start, presentation start and end time
+    type Stc = (u32, u32, u32);
+
+    //spn_atc_start, offset_stc_id and its STC sequences
+    fn sequence_info(atc: &[(u32, u8, &[Stc])]) -> Vec<u8> {
+        let mut d = vec![0, atc.len() as u8];
+        for (spn_atc_start, offset_stc_id, stc) in atc {
+            d.extend(spn_atc_start.to_be_bytes());
+            d.push(stc.len() as u8);
+            d.push(*offset_stc_id);
+            for (spn, start, end) in stc.iter() {
+                d.extend(0x1001u16.to_be_bytes());
+                d.extend(spn.to_be_bytes());
+                d.extend(start.to_be_bytes());
+                d.extend(end.to_be_bytes());
+            }
+        }
+        d
+    }
 
-    parse_cpi(&cpi)
+    #[test]
+    fn reads_stc_sequences() {
+        let buf = sequence_info(&[
+            (0, 0, &[(0, 27_000, 900_000), (5_000, 45_000, 450_000)]),
+            (9_000, 2, &[(9_000, 90_000, 180_000)]),
+        ]);
+        let atc_sequences = parse_sequence_info(&buf).unwrap();
+        assert_eq!(atc_sequences.len(), 2);
+
+        let res = CLPIResult {
+            clip_info: ClipInfo::default(),
+            clip_marks: Vec::new(),
+            extension_data: Vec::new(),
+            ep_streams: Vec::new(),
+            atc_sequences,
+            programs: Vec::new(),
+        };
+        let got: Vec<_> = res
+            .stc_sequences()
+            .iter()
+            .map(|s| {
+                (
+                    s.id,
+                    s.spn_stc_start,
+                    s.presentation_start_time,
+                    s.presentation_end_time,
+                )
+            })
+            .collect();
+        //ids count up from offset_stc_id of their ATC sequence
+        assert_eq!(
+            got,
+            [
+                (0, 0, 27_000, 900_000),
+                (1, 5_000, 45_000, 450_000),
+                (2, 9_000, 90_000, 180_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_sequence_info() {
+        let mut buf = sequence_info(&[(0, 0, &[(0, 27_000, 900_000)])]);
+        buf.truncate(buf.len() - 2);
+        assert!(parse_sequence_info(&buf).is_err());
+    }
+
+    fn clip_info(num_source_packets: u32) -> Vec<u8> {
+        let mut d = vec![0, 0, 1, 1];
+        d.extend(1u32.to_be_bytes());
+        d.extend(48_000_000u32.to_be_bytes());
+        d.extend(num_source_packets.to_be_bytes());
+        d.resize(d.len() + 128, 0);
+        d.extend(5u16.to_be_bytes());
+        d.push(0x80);
+        d.extend(b"HDMV");
+        d
+    }
+
+    #[test]
+    fn reads_clip_info() {
+        let info = parse_clip_info(&clip_info(123_456)).unwrap();
+        assert_eq!(info.clip_stream_type, 1);
+        assert_eq!(info.application_type, 1);
+        assert!(info.is_atc_delta);
+        assert_eq!(info.ts_recording_rate, 48_000_000);
+        assert_eq!(info.num_source_packets, 123_456);
+        assert_eq!(info.format_identifier, "HDMV");
+    }
+
+    #[test]
+    fn reads_clip_marks() {
+        let mut buf = vec![0, 0, 0, 2];
+        for (mark_type, time_stamp) in [(1u8, 90_000u32), (2, 180_000)] {
+            buf.extend([0, mark_type]);
+            buf.extend(0u16.to_be_bytes());
+            buf.extend(time_stamp.to_be_bytes());
+            buf.extend(0x1011u16.to_be_bytes());
+            buf.extend(45_000u32.to_be_bytes());
+        }
+        let marks = parse_clip_mark(&buf).unwrap();
+        let got: Vec<_> = marks
+            .iter()
+            .map(|m| (m.mark_type, m.time_stamp, m.entry_es_pid, m.duration))
+            .collect();
+        assert_eq!(
+            got,
+            [(1, 90_000, 0x1011, 45_000), (2, 180_000, 0x1011, 45_000)]
+        );
+
+        //an empty ClipMark block has no marks
+        assert!(parse_clip_mark(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_extension_data() {
+        let mut buf = 32u32.to_be_bytes().to_vec();
+        buf.extend([0, 0, 0, 1]);
+        buf.extend(2u16.to_be_bytes());
+        buf.extend(5u16.to_be_bytes());
+        buf.extend(32u32.to_be_bytes());
+        buf.extend(16u32.to_be_bytes());
+        let entries = parse_extension_data(&buf).unwrap();
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!((e.id1, e.id2, e.start_address, e.length), (2, 5, 32, 16));
+
+        assert!(parse_extension_data(&[]).unwrap().is_empty());
+    }
+
+    struct EpMap {
+        pid: u16,
+        //fine_id, pts and spn as coded
+        coarse: Vec<(u32, u16, u32)>,
+        fine: Vec<(u16, u32)>,
+    }
+
+    fn cpi(maps: &[EpMap]) -> Vec<u8> {
+        //CPI type, reserved and the stream entries
+        let mut d = vec![0, 1, 0, maps.len() as u8];
+        let mut tables = Vec::new();
+        //addresses are relative to the byte after the CPI type
+        let mut addr = 2 + 12 * maps.len();
+        for m in maps {
+            let entry = (m.pid as u128) << 80
+                | 1 << 66
+                | (m.coarse.len() as u128) << 50
+                | (m.fine.len() as u128) << 32
+                | addr as u128;
+            d.extend(&entry.to_be_bytes()[4..]);
+
+            let mut table = (4 + 8 * m.coarse.len() as u32).to_be_bytes().to_vec();
+            for &(fine_id, pts, spn) in &m.coarse {
+                let c = (fine_id as u64) << 46 | (pts as u64) << 32 | spn as u64;
+                table.extend(c.to_be_bytes());
+            }
+            for &(pts, spn) in &m.fine {
+                table.extend(((pts as u32) << 17 | spn).to_be_bytes());
+            }
+            addr += table.len();
+            tables.extend(table);
+        }
+        d.extend(tables);
+        d
+    }
+
+    fn block(b: &[u8]) -> Vec<u8> {
+        let mut d = (b.len() as u32).to_be_bytes().to_vec();
+        d.extend(b);
+        d
+    }
+
+    fn clpi(program: &[u8], cpi: &[u8]) -> Vec<u8> {
+        let mut d = b"HDMV0200".to_vec();
+        let clip_info = block(&clip_info(0));
+        let sequence = block(&sequence_info(&[(0, 0, &[(0, 0, 90_000)])]));
+        let seq_addr = 40 + clip_info.len() as u32;
+        let prog_addr = seq_addr + sequence.len() as u32;
+        let cpi_addr = prog_addr + 4 + program.len() as u32;
+        for addr in [seq_addr, prog_addr, cpi_addr, 0, 0] {
+            d.extend(addr.to_be_bytes());
+        }
+        d.resize(40, 0);
+        d.extend(clip_info);
+        d.extend(sequence);
+        d.extend(block(program));
+        d.extend(block(cpi));
+        d
+    }
+
+    fn video_ep_map() -> EpMap {
+        EpMap {
+            pid: 0x1011,
+            coarse: vec![(0, 0x3, 0x20005), (2, 0x4, 0x40000)],
+            fine: vec![(1, 5), (2, 0x100), (0x7FF, 0x1FFFF)],
+        }
+    }
+
+    #[test]
+    fn combines_ep_map_entries() {
+        let data = clpi(&program_info(&[]), &cpi(&[video_ep_map()]));
+        let res = read_clpi(&mut Cursor::new(data)).unwrap();
+        let got: Vec<_> = res.ep_streams[0]
+            .combined
+            .iter()
+            .map(|c| (c.pts, c.spn))
+            .collect();
+        //the coarse spn keeps its bits above 17, the coarse pts its bits above 18
+        assert_eq!(
+            got,
+            [
+                ((2 << 18) + (1 << 8), 0x20005),
+                ((2 << 18) + (2 << 8), 0x20100),
+                ((4 << 18) + (0x7FF << 8), 0x5FFFF)
+            ]
+        );
+    }
+
+    #[test]
+    fn selects_ep_stream_by_pid() {
+        let audio = EpMap {
+            pid: 0x1100,
+            coarse: vec![(0, 0, 0)],
+            fine: vec![(0, 0)],
+        };
+        let program = program_info(&[
+            stream(0x1100, &[0x80, 0x31, 0, 0, 0, 0]),
+            stream(0x1011, &[0x1B, 0x66, 0, 0, 0]),
+        ]);
+        let data = clpi(&program, &cpi(&[audio, video_ep_map()]));
+        let res = read_clpi(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(res.ep_streams.len(), 2);
+        assert_eq!(res.ep_stream(None).unwrap().pid, 0x1100);
+        let video = res.ep_stream(Some(0x1011)).unwrap();
+        assert_eq!(video.combined.len(), 3);
+        assert_eq!(res.frame_rate(0x1011), Some((50, 1)));
+
+        match res.ep_stream(Some(0x1200)) {
+            Err(Error::NoEpMap { pid, available }) => {
+                assert_eq!(pid, Some(0x1200));
+                assert_eq!(available, [0x1100, 0x1011]);
+            }
+            _ => panic!("expected NoEpMap"),
+        }
+    }
+
+    #[test]
+    fn rejects_fine_ids_past_the_table() {
+        let map = EpMap {
+            pid: 0x1011,
+            coarse: vec![(0, 0, 0), (5, 0, 0)],
+            fine: vec![(0, 0), (1, 1)],
+        };
+        let data = clpi(&program_info(&[]), &cpi(&[map]));
+        assert!(matches!(
+            read_clpi(&mut Cursor::new(data)),
+            Err(Error::InvalidClpi(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_blocks_past_the_end() {
+        let mut data = clpi(&program_info(&[]), &cpi(&[video_ep_map()]));
+        let cpi_addr = u32::from_be_bytes(data[16..20].try_into().unwrap()) as usize;
+        data[cpi_addr..cpi_addr + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            read_clpi(&mut Cursor::new(data)),
+            Err(Error::InvalidClpi(_))
+        ));
+    }
+
+    #[test]
+    fn frame_rate_codes() {
+        assert_eq!(frame_rate_from_code(1), Some((24000, 1001)));
+        assert_eq!(frame_rate_from_code(3), Some((25, 1)));
+        assert_eq!(frame_rate_from_code(7), Some((60000, 1001)));
+        assert_eq!(frame_rate_from_code(0), None);
+        assert_eq!(frame_rate_from_code(5), None);
+    }
 }
@@ -0,0 +1,105 @@
+use std::{fmt, io};
+
+use ffmpeg_sys::AVCodecID;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    //structure of the CLPI doesn't make sense
+    InvalidClpi(String),
+    InvalidMpls(String),
+    //not a UDF image this reader handles, or the file isn't in it
+    Udf(String),
+    //cached index that doesn't belong to the clip or is broken, it gets rebuilt
+    InvalidIndex(String),
+    NoEpMap {
+        pid: Option<u16>,
+        available: Vec<u16>,
+    },
+    NoDecoder(AVCodecID),
+    //no PES of the pid, or the PMT doesn't list it
+    StreamNotFound(u16),
+    //AVERROR returned by `call`
+    Ffmpeg {
+        call: &'static str,
+        code: i32,
+    },
+    //the stream ended before the requested frame, truncated m2ts or wrong frame count
+    EndOfStream,
+    //frame number before the first keyframe, or a clip without any
+    NoKeyframe(u64),
+    //the audio stream can't be written to a wav
+    Audio(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn av_error_string(code: i32) -> String {
+    let mut buf = [0u8; 256];
+    unsafe {
+        if ffmpeg_sys::av_strerror(code, buf.as_mut_ptr() as _, buf.len()) < 0 {
+            return format!("error {}", code);
+        }
+    }
+    let len = buf.iter().position(|e| *e == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidClpi(e) => write!(f, "Invalid CLPI: {}", e),
+            Error::InvalidMpls(e) => write!(f, "Invalid MPLS: {}", e),
+            Error::Udf(e) => write!(f, "{}", e),
+            Error::InvalidIndex(e) => write!(f, "Invalid index cache: {}", e),
+            Error::NoEpMap { pid, available } => write!(
+                f,
+                "No EP map for stream pid {:?}, available: {:?}",
+                pid, available
+            ),
+            Error::NoDecoder(e) => write!(f, "No decoder for {:?}", e),
+            Error::StreamNotFound(e) => write!(f, "Stream 0x{:04X} not found in the m2ts", e),
+            Error::Ffmpeg { call, code } => write!(f, "{}: {}", call, av_error_string(*code)),
+            Error::EndOfStream => write!(f, "Stream ended before the requested frame"),
+            Error::NoKeyframe(e) => write!(f, "No keyframe before frame {}", e),
+            Error::Audio(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        //one of ours that went through a Read impl
+        if e.get_ref().is_some_and(|e| e.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+//bit fields read past the end of their blob
+impl From<bitreader::BitReaderError> for Error {
+    fn from(e: bitreader::BitReaderError) -> Error {
+        Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, e))
+    }
+}
+
+//for the Read impls over sources
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
@@ -1,89 +1,315 @@
 use std::{
-    fs::File,
-    io::{Read, Seek, SeekFrom},
+    io,
     sync::{Arc, Mutex},
 };
 
+use crate::error::Result;
+use crate::stream_source::{read_exact_at, StreamSource};
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub bytes_read: u64,
+}
+
+struct FileCacheState {
+    //one gop per keyframe, shared so it can be copied from without the lock
+    inner: Vec<Option<Arc<Vec<u8>>>>,
+    //tick of the last read from each gop
+    last_used: Vec<u64>,
+
+    //0 for unbounded
+    budget: u64,
+    cached_bytes: u64,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl FileCacheState {
+    //drops least recently used gops until `incoming` more bytes fit, `keep` is the gop being read
+    fn make_room(&mut self, incoming: u64, keep: usize) {
+        if self.budget == 0 {
+            return;
+        }
+        while self.cached_bytes + incoming > self.budget {
+            let oldest = (0..self.inner.len())
+                .filter(|e| *e != keep && self.inner[*e].is_some())
+                .min_by_key(|e| self.last_used[*e]);
+            match oldest {
+                Some(i) => {
+                    let buf = self.inner[i].take().unwrap();
+                    self.cached_bytes -= buf.len() as u64;
+                    self.stats.evictions += 1;
+                }
+                //a single gop bigger than the budget still has to be read
+                None => break,
+            }
+        }
+    }
+}
+
+//reads whole gops of another source into memory, segmented on the spn of the keyframes
 pub struct FileCacheBackend {
-    pub inner: Vec<(Option<Vec<u8>>, super::predict::PredictedKeyFrame)>,
-    pub f: File,
-    pub file_size: u64,
+    state: Mutex<FileCacheState>,
+    src: Arc<dyn StreamSource>,
+    file_size: u64,
+    //byte offset of every gop, the first one also holds what comes before its keyframe
+    starts: Vec<u64>,
 }
 
 impl FileCacheBackend {
-    pub fn new(f: File, pred: &[super::predict::PredictedKeyFrame]) -> FileCacheBackend {
+    pub fn new(
+        src: Arc<dyn StreamSource>,
+        pred: &[super::predict::PredictedKeyFrame],
+        budget: u64,
+    ) -> FileCacheBackend {
+        let mut starts: Vec<u64> = pred.iter().map(|e| e.spn as u64 * 192).collect();
+        if let Some(first) = starts.first_mut() {
+            *first = 0;
+        }
         FileCacheBackend {
-            file_size: f.metadata().unwrap().len(),
-            f,
-            inner: pred.iter().map(|e| (None, e.clone())).collect(),
+            file_size: src.size(),
+            src,
+            starts,
+            state: Mutex::new(FileCacheState {
+                inner: vec![None; pred.len()],
+                last_used: vec![0; pred.len()],
+                budget,
+                cached_bytes: 0,
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    //gop `i` spanning [pos, next_pos), the lock isn't held while it is read from the source
+    fn gop(&self, i: usize, pos: u64, next_pos: u64) -> Result<Arc<Vec<u8>>> {
+        {
+            let mut lck = self.state.lock().unwrap();
+            lck.tick += 1;
+            let tick = lck.tick;
+            lck.last_used[i] = tick;
+            if let Some(cached) = &lck.inner[i] {
+                let cached = cached.clone();
+                lck.stats.hits += 1;
+                return Ok(cached);
+            }
+            lck.stats.misses += 1;
+        }
+
+        let mut buf = vec![0u8; (next_pos - pos) as usize];
+        read_exact_at(self.src.as_ref(), pos, &mut buf)?;
+        let buf = Arc::new(buf);
+
+        let mut lck = self.state.lock().unwrap();
+        lck.stats.bytes_read += buf.len() as u64;
+        //another thread could have read it meanwhile
+        if let Some(cached) = &lck.inner[i] {
+            return Ok(cached.clone());
+        }
+        lck.make_room(buf.len() as u64, i);
+        lck.cached_bytes += buf.len() as u64;
+        lck.inner[i] = Some(buf.clone());
+        Ok(buf)
+    }
+}
+
+impl StreamSource for FileCacheBackend {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        let mut read_sum = 0;
+        let mut head = pos;
+
+        while read_sum < b.len() && head < self.file_size && !self.starts.is_empty() {
+            let i = self.starts.partition_point(|e| *e <= head) - 1;
+            let pos = self.starts[i];
+            let next_pos = match self.starts.get(i + 1) {
+                Some(e) => *e,
+                None => self.file_size,
+            };
+
+            let cached = self.gop(i, pos, next_pos)?;
+            let cached_offset = (head - pos) as usize;
+            let to_read = (b.len() - read_sum).min(cached.len() - cached_offset);
+
+            b[read_sum..read_sum + to_read]
+                .copy_from_slice(&cached[cached_offset..cached_offset + to_read]);
+            read_sum += to_read;
+            head += to_read as u64;
+        }
+        Ok(read_sum)
+    }
+
+    fn size(&self) -> u64 {
+        self.file_size
+    }
+
+    fn id(&self) -> String {
+        self.src.id()
+    }
+}
+
+impl Drop for FileCacheBackend {
+    fn drop(&mut self) {
+        if std::env::var_os("BDNGSP_CACHE_STATS").is_some() {
+            eprintln!("bdngsp cache: {:?}", self.state.lock().unwrap().stats);
         }
     }
 }
 
 pub struct FCached {
-    pred: Arc<Mutex<FileCacheBackend>>,
+    backend: Arc<dyn StreamSource>,
     seek_head: u64,
 }
 
 impl FCached {
-    pub fn new(backend: Arc<Mutex<FileCacheBackend>>) -> FCached {
+    pub fn new(backend: Arc<dyn StreamSource>) -> FCached {
         FCached {
-            pred: backend,
+            backend,
             seek_head: 0,
         }
     }
 
     pub fn seek(&mut self, a: u64) {
         self.seek_head = a;
-        //    self.f.seek(SeekFrom::Start(self.seek_head)).unwrap();
     }
+}
 
-    pub fn read(&mut self, b: &mut [u8]) -> u64 {
-        // let mut lck = self.pred.lock().unwrap();
-        // lck.f.seek(SeekFrom::Start(self.seek_head)).unwrap();
-        // let rr =  lck.f.read(b).unwrap();
-        // self.seek_head += rr as u64;
-        // return rr as u64;
-        let mut lck = self.pred.lock().unwrap();
-        let mut read_sum = 0;
+impl io::Read for FCached {
+    fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
+        let rr = self.backend.read_at(self.seek_head, b)?;
+        self.seek_head += rr as u64;
+        Ok(rr)
+    }
+}
 
-        for i in 0..lck.inner.len() {
-            let pos = if i == 0 {
-                0
-            } else {
-                lck.inner[i].1.spn as u64 * 192
-            };
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
 
-            let next_pos = if i == lck.inner.len() - 1 {
-                lck.file_size
-            } else {
-                lck.inner[i + 1].1.spn as u64 * 192
-            };
-            if self.seek_head >= pos && self.seek_head < next_pos {
-                if lck.inner[i].0.is_none() {
-                    let mut buf = vec![0u8; (next_pos - pos) as usize];
+    use super::*;
+    use crate::predict::PredictedKeyFrame;
 
-                    lck.f.seek(SeekFrom::Start(pos)).unwrap();
-                    lck.f.read_exact(&mut buf).unwrap();
-                    lck.inner[i].0 = Some(buf);
-                }
-                let cached = lck.inner[i].0.as_ref().unwrap();
-                let cached_offset = self.seek_head - pos;
+    struct Bytes(Vec<u8>);
+
+    impl StreamSource for Bytes {
+        fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+            let src = self.0.get(pos as usize..).unwrap_or(&[]);
+            let to_read = b.len().min(src.len());
+            b[..to_read].copy_from_slice(&src[..to_read]);
+            Ok(to_read)
+        }
+
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
 
-                let to_read = (b.len() - read_sum).min(cached.len() - cached_offset as usize);
+        fn id(&self) -> String {
+            "bytes".to_owned()
+        }
+    }
+
+    //4 gops of 10 source packets, the first keyframe isn't at the start
+    fn backend(budget: u64) -> (Vec<u8>, FileCacheBackend) {
+        let data: Vec<u8> = (0..40 * 192).map(|e| (e % 251) as u8).collect();
+        let pred: Vec<_> = [3, 10, 20, 30]
+            .iter()
+            .enumerate()
+            .map(|(i, &spn)| PredictedKeyFrame {
+                number: i as u64 * 24,
+                pts: 0,
+                spn,
+            })
+            .collect();
+        let src = Arc::new(Bytes(data.clone()));
+        (data, FileCacheBackend::new(src, &pred, budget))
+    }
 
-                b[read_sum..read_sum + to_read as usize].copy_from_slice(
-                    &cached[cached_offset as usize..cached_offset as usize + to_read as usize],
-                );
-                read_sum += to_read;
+    fn stats(b: &FileCacheBackend) -> CacheStats {
+        b.state.lock().unwrap().stats
+    }
 
-                self.seek_head += to_read as u64;
+    #[test]
+    fn evicts_least_recently_used() {
+        //room for two gops
+        let (_, b) = backend(2 * 10 * 192);
+        let mut buf = [0u8; 1];
+        for gop in [0, 1, 0, 2] {
+            b.read_at(gop * 10 * 192 + 5, &mut buf).unwrap();
+        }
+        let s = stats(&b);
+        assert_eq!((s.hits, s.misses, s.evictions), (1, 3, 1));
+        assert_eq!(s.bytes_read, 3 * 10 * 192);
 
-                if b.len() == read_sum {
-                    return read_sum as u64;
-                }
+        //gop 1 was the one dropped
+        b.read_at(0, &mut buf).unwrap();
+        b.read_at(10 * 192, &mut buf).unwrap();
+        let s = stats(&b);
+        assert_eq!((s.hits, s.misses, s.evictions), (2, 4, 2));
+    }
+
+    #[test]
+    fn unbounded_keeps_everything() {
+        let (_, b) = backend(0);
+        let mut buf = vec![0u8; 40 * 192];
+        b.read_at(0, &mut buf).unwrap();
+        b.read_at(0, &mut buf).unwrap();
+        let s = stats(&b);
+        assert_eq!((s.hits, s.misses, s.evictions), (4, 4, 0));
+    }
+
+    #[test]
+    fn reads_across_gops() {
+        let (data, b) = backend(10 * 192);
+        //before the first keyframe, through all gops
+        let mut buf = vec![0u8; 35 * 192];
+        assert_eq!(b.read_at(100, &mut buf).unwrap(), buf.len());
+        assert_eq!(buf, data[100..100 + buf.len()]);
+
+        //short at the end
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(b.read_at(data.len() as u64 - 10, &mut buf).unwrap(), 10);
+        assert_eq!(b.read_at(data.len() as u64, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_from_threads() {
+        let (data, b) = backend(10 * 192);
+        let b = Arc::new(b);
+        let threads: Vec<_> = (0..4u64)
+            .map(|t| {
+                let b = b.clone();
+                std::thread::spawn(move || {
+                    let mut out = Vec::new();
+                    for i in 0..20 {
+                        let pos = (t * 997 + i * 389) % (40 * 192 - 300);
+                        let mut buf = vec![0u8; 300];
+                        b.read_at(pos, &mut buf).unwrap();
+                        out.push((pos as usize, buf));
+                    }
+                    out
+                })
+            })
+            .collect();
+        for t in threads {
+            for (pos, buf) in t.join().unwrap() {
+                assert_eq!(buf, data[pos..pos + 300]);
             }
         }
-        read_sum as u64
+        let lck = b.state.lock().unwrap();
+        assert!(lck.cached_bytes <= lck.budget);
+    }
+
+    #[test]
+    fn fcached_reads_sequentially() {
+        let (data, b) = backend(0);
+        let mut f = FCached::new(Arc::new(b));
+        f.seek(1000);
+        let mut buf = vec![0u8; 5000];
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[1000..6000]);
+        f.read_exact(&mut buf[..100]).unwrap();
+        assert_eq!(buf[..100], data[6000..6100]);
     }
 }
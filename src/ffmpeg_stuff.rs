@@ -1,29 +1,43 @@
-use std::{
-    error::Error,
-    ffi::{c_void, CString},
-    sync::{Arc, Mutex},
-};
+use ffmpeg_sys::{AVCodecContext, AVCodecID, AVCodecParserContext, AVFrame, AVPixelFormat};
 
-use ffmpeg_sys::{AVCodec, AVCodecContext, AVFormatContext, AVFrame, AVPixelFormat};
+use crate::error::{Error, Result};
+use crate::fcache::FCached;
+use crate::m2ts;
 
 pub use ffmpeg_sys;
 use ffmpeg_sys::AVCodecID::*;
 use ffmpeg_sys::*;
 
-pub struct CustomFileContext {
-    pub buffer: [u8; 32 * 1024],
-    pub the_file: Arc<Mutex<super::fcache::FCached>>,
+//splits audio PES into frames, they can hold several or only part of one
+struct AudioParser {
+    ctx: *mut AVCodecParserContext,
+    //PES payload not parsed yet
+    input: Vec<u8>,
+    pos: usize,
+    //of the PES, only passed with its first bytes
+    pts: i64,
+    dts: i64,
+    //no PES left, the parser gets flushed
+    eof: bool,
+}
+
+impl Drop for AudioParser {
+    fn drop(&mut self) {
+        unsafe {
+            av_parser_close(self.ctx);
+        }
+    }
 }
 
 pub struct DecoderSetup {
-    pub incodec: *mut AVCodec,
     pub avctx: *mut AVCodecContext,
-    pub fctx: *mut AVFormatContext,
     pub inpkt: *mut AVPacket,
-    pub stream_index: i32,
-    pub frame_rate_d: u32,
-    pub frame_rate_n: u32,
-    pub rc: Arc<CustomFileContext>,
+    demuxer: m2ts::Demuxer<FCached>,
+    codec_id: AVCodecID,
+    //None for video, BD video PES hold exactly one access unit
+    parser: Option<AudioParser>,
+    //the stream ended and the decoder was sent the flush packet
+    pub draining: bool,
 }
 
 unsafe impl Sync for DecoderSetup {}
@@ -32,121 +46,141 @@ impl Drop for DecoderSetup {
     fn drop(&mut self) {
         unsafe {
             avcodec_free_context(&mut self.avctx);
-            avformat_free_context(self.fctx);
+            av_packet_free(&mut self.inpkt);
         }
     }
 }
 
-pub unsafe fn setup_decoder(
-    stream_file: Arc<Mutex<super::fcache::FCached>>,
-    start_spn: u32,
-    stream_pid: u16,
-) -> Result<DecoderSetup, Box<dyn Error>> {
-    unsafe extern "C" fn file_seek(opaque: *mut c_void, offset: i64, whence: i32) -> i64 {
-        let refa = opaque as *mut CustomFileContext;
-        let refa = &mut (*refa);
-        if whence == SEEK_SET {
-            let mut ff = refa.the_file.lock().unwrap();
-
-            ff.seek(offset as _);
-            0
-        } else if whence == AVSEEK_SIZE {
-            0
-        } else {
-            panic!("Invalid seek");
-        }
+//CLPI ProgramInfo stream_coding_type to decoder
+pub fn codec_for_coding_type(coding_type: u8) -> Option<AVCodecID> {
+    match coding_type {
+        0x01 => Some(AV_CODEC_ID_MPEG1VIDEO),
+        0x02 => Some(AV_CODEC_ID_MPEG2VIDEO),
+        0x1B => Some(AV_CODEC_ID_H264),
+        0x20 => Some(AV_CODEC_ID_H264), //MVC, only the base view gets decoded
+        0x24 => Some(AV_CODEC_ID_HEVC),
+        0xEA => Some(AV_CODEC_ID_VC1),
+        0x80 => Some(AV_CODEC_ID_PCM_BLURAY),
+        0x81 => Some(AV_CODEC_ID_AC3),
+        0x82 | 0x85 | 0x86 | 0xA2 => Some(AV_CODEC_ID_DTS),
+        0x83 => Some(AV_CODEC_ID_TRUEHD),
+        0x84 | 0xA1 => Some(AV_CODEC_ID_EAC3), //0xA1 is secondary audio, always E-AC-3
+        _ => None,
     }
+}
 
-    unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, size: i32) -> i32 {
-        // println!("read_packet {}",size);
-        let refa = opaque as *mut CustomFileContext;
-        let refa = &mut (*refa);
-        let slc = std::slice::from_raw_parts_mut(buf, size as usize);
-        let mut ff = refa.the_file.lock().unwrap();
+fn is_video_codec(codec_id: AVCodecID) -> bool {
+    matches!(
+        codec_id,
+        AV_CODEC_ID_MPEG1VIDEO
+            | AV_CODEC_ID_MPEG2VIDEO
+            | AV_CODEC_ID_H264
+            | AV_CODEC_ID_HEVC
+            | AV_CODEC_ID_VC1
+    )
+}
 
-        let ree = ff.read(slc);
-        ree as _
+//the VC-1 decoder wants the sequence header and entry point as extradata,
+//on a BD they are in front of the first frame (start code 0x0D) of every keyframe
+fn vc1_extradata(data: &[u8]) -> Option<&[u8]> {
+    let frame = data.windows(4).position(|e| e == [0, 0, 1, 0x0D])?;
+    let header = &data[..frame];
+    if !header.windows(4).any(|e| e == [0, 0, 1, 0x0F]) {
+        return None;
     }
+    Some(header)
+}
 
-    let incodec = avcodec_find_decoder(AV_CODEC_ID_H264);
+//the PES are read directly, without avformat probing the stream on every seek
+pub unsafe fn setup_decoder(
+    mut stream_file: FCached,
+    start_spn: u32,
+    stream_pid: u16,
+    codec_id: AVCodecID,
+) -> Result<DecoderSetup> {
+    let incodec = avcodec_find_decoder(codec_id);
+    if incodec.is_null() {
+        return Err(Error::NoDecoder(codec_id));
+    }
     let avctx = avcodec_alloc_context3(incodec);
-    let in_thing = avcodec_open2(avctx, incodec, std::ptr::null_mut::<*mut AVDictionary>());
+    (*avctx).pkt_timebase = AVRational {
+        num: 1,
+        den: 90_000,
+    };
 
-    if in_thing != 0 {
-        return Err(Box::new(simple_error::simple_error!(
-            "avcodec_open2: {}",
-            in_thing
-        )));
-    }
-    let mut fctx = avformat_alloc_context();
-
-    (*fctx).iformat = av_find_input_format(CString::new("mpegts")?.as_ptr());
-
-    let rc = Arc::new(CustomFileContext {
-        buffer: [0u8; 32 * 1024],
-        the_file: stream_file,
-    });
-    (*fctx).pb = avio_alloc_context(
-        rc.buffer.as_ptr() as _,
-        rc.buffer.len() as _,
-        0,
-        (rc.as_ref() as *const CustomFileContext) as *mut c_void,
-        Some(read_packet),
-        None,
-        Some(file_seek),
-    );
-    let seek_dst = start_spn as i64 * 192;
-    let rr = avio_seek((*fctx).pb, seek_dst, SEEK_SET);
-    if rr != seek_dst {
-        return Err(Box::new(simple_error::simple_error!("avio_seek: {}", rr)));
-    }
-    let av_ret = avformat_open_input(
-        &mut fctx,
-        CString::new("dunno")?.as_ptr(),
-        std::ptr::null_mut() as _,
-        std::ptr::null_mut() as _,
-    );
-    if av_ret != 0 {
-        return Err(Box::new(simple_error::simple_error!(
-            "avformat_open_input: {}",
-            av_ret
-        )));
-    }
+    stream_file.seek(start_spn as u64 * 192);
 
-    avcodec_flush_buffers(avctx);
-    avformat_find_stream_info(fctx, std::ptr::null_mut() as _);
+    let parser = if is_video_codec(codec_id) {
+        None
+    } else {
+        let ctx = av_parser_init(codec_id as _);
+        //LPCM has none, its PES are whole frames
+        if ctx.is_null() {
+            None
+        } else {
+            Some(AudioParser {
+                ctx,
+                input: Vec::new(),
+                pos: 0,
+                pts: AV_NOPTS_VALUE,
+                dts: AV_NOPTS_VALUE,
+                eof: false,
+            })
+        }
+    };
+
+    let mut setup = DecoderSetup {
+        avctx,
+        inpkt: av_packet_alloc(),
+        demuxer: m2ts::Demuxer::new(stream_file, stream_pid),
+        codec_id,
+        parser,
+        draining: false,
+    };
 
-    let mut stream_index = 0;
+    //stays in inpkt as the first one to decode
+    match next_packet(&mut setup) {
+        Err(Error::EndOfStream) => return Err(Error::StreamNotFound(stream_pid)),
+        e => e?,
+    }
 
-    let mut frame_rate_d = 0;
-    let mut frame_rate_n = 0;
-    for i in 0..(*fctx).nb_streams {
-        let strm = (*(*fctx).streams).offset(i as _);
-        if (*strm).id == stream_pid as _ {
-            stream_index = i as i32;
-            let asd = (*strm).r_frame_rate;
-            frame_rate_d = asd.den as u32;
-            frame_rate_n = asd.num as u32;
+    if codec_id == AV_CODEC_ID_VC1 {
+        let data = std::slice::from_raw_parts((*setup.inpkt).data, (*setup.inpkt).size as usize);
+        if let Some(header) = vc1_extradata(data) {
+            let extradata =
+                av_mallocz(header.len() + AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+            std::ptr::copy_nonoverlapping(header.as_ptr(), extradata, header.len());
+            (*avctx).extradata = extradata;
+            (*avctx).extradata_size = header.len() as i32;
         }
     }
-    let inpkt = av_packet_alloc();
 
-    Ok(DecoderSetup {
-        avctx,
-        fctx,
-        incodec,
-        stream_index,
-        rc,
-        frame_rate_d,
-        frame_rate_n,
-        inpkt,
-    })
+    let in_thing = avcodec_open2(avctx, incodec, std::ptr::null_mut::<*mut AVDictionary>());
+    if in_thing != 0 {
+        return Err(Error::Ffmpeg {
+            call: "avcodec_open2",
+            code: in_thing,
+        });
+    }
+
+    Ok(setup)
 }
 
 pub struct AutoFreeFrame {
     pub frame: *mut AVFrame,
 }
 
+impl AutoFreeFrame {
+    //shares the buffers, nothing gets copied
+    pub fn new_ref(&self) -> AutoFreeFrame {
+        unsafe {
+            AutoFreeFrame {
+                frame: ffmpeg_sys::av_frame_clone(self.frame),
+            }
+        }
+    }
+}
+
 impl Drop for AutoFreeFrame {
     fn drop(&mut self) {
         unsafe {
@@ -155,108 +189,291 @@ impl Drop for AutoFreeFrame {
     }
 }
 
-pub unsafe fn read_nth_frame(our_shit: &mut DecoderSetup, frame_offset: u64) -> AutoFreeFrame {
-    use ffmpeg_sys::*;
-    //println!("{}", frame_offset);
-    // let inpkt = av_packet_alloc();
-    let inpkt = our_shit.inpkt;
+fn ts_or_nopts(ts: Option<u64>) -> i64 {
+    ts.map_or(AV_NOPTS_VALUE, |e| e as i64)
+}
 
-    let fctx = our_shit.fctx;
-    let avctx = our_shit.avctx;
+unsafe fn fill_packet(pkt: *mut AVPacket, data: &[u8], pts: i64, dts: i64) -> Result<()> {
+    let ret = av_new_packet(pkt, data.len() as i32);
+    if ret < 0 {
+        return Err(Error::Ffmpeg {
+            call: "av_new_packet",
+            code: ret,
+        });
+    }
+    std::ptr::copy_nonoverlapping(data.as_ptr(), (*pkt).data, data.len());
+    (*pkt).pts = pts;
+    (*pkt).dts = dts;
+    Ok(())
+}
 
-    if (*inpkt).pts < 0 {
-        //println!("Did read");
-        av_read_frame(fctx, inpkt);
+//next PES of the decoded stream
+unsafe fn next_pes(our_shit: &mut DecoderSetup) -> Result<Option<m2ts::Pes>> {
+    loop {
+        let pes = match our_shit.demuxer.next_pes()? {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        //an empty packet would flush the decoder
+        if pes.data.is_empty() {
+            continue;
+        }
+        //the AC-3 core on a TrueHD pid
+        if our_shit.codec_id == AV_CODEC_ID_TRUEHD && pes.is_ac3_core() {
+            continue;
+        }
+        return Ok(Some(pes));
     }
+}
+
+//reads the next packet of the decoded stream into inpkt
+pub unsafe fn next_packet(our_shit: &mut DecoderSetup) -> Result<()> {
+    av_packet_unref(our_shit.inpkt);
+    loop {
+        let parser = match &mut our_shit.parser {
+            Some(e) => e,
+            None => {
+                let pes = match next_pes(our_shit)? {
+                    Some(e) => e,
+                    None => return Err(Error::EndOfStream),
+                };
+                return fill_packet(
+                    our_shit.inpkt,
+                    &pes.data,
+                    ts_or_nopts(pes.pts),
+                    ts_or_nopts(pes.dts),
+                );
+            }
+        };
+
+        //empty input at the end returns what the parser still holds
+        if parser.pos < parser.input.len() || parser.eof {
+            let input = &parser.input[parser.pos..];
+            let mut out = std::ptr::null_mut();
+            let mut out_size = 0;
+            let used = av_parser_parse2(
+                parser.ctx,
+                our_shit.avctx,
+                &mut out,
+                &mut out_size,
+                input.as_ptr(),
+                input.len() as i32,
+                parser.pts,
+                parser.dts,
+                0,
+            );
+            parser.pos += used.max(0) as usize;
+            parser.pts = AV_NOPTS_VALUE;
+            parser.dts = AV_NOPTS_VALUE;
+            if out_size > 0 {
+                let data = std::slice::from_raw_parts(out, out_size as usize);
+                return fill_packet(our_shit.inpkt, data, (*parser.ctx).pts, (*parser.ctx).dts);
+            }
+            if parser.eof {
+                return Err(Error::EndOfStream);
+            }
+            continue;
+        }
 
-    while (*inpkt).stream_index != our_shit.stream_index {
-        av_packet_unref(inpkt);
-        av_read_frame(fctx, inpkt);
+        match next_pes(our_shit)? {
+            Some(pes) => {
+                let parser = our_shit.parser.as_mut().unwrap();
+                parser.input = pes.data;
+                parser.pos = 0;
+                parser.pts = ts_or_nopts(pes.pts);
+                parser.dts = ts_or_nopts(pes.dts);
+            }
+            None => our_shit.parser.as_mut().unwrap().eof = true,
+        }
     }
+}
 
-    let out = av_frame_alloc();
-    // println!("iffset {}", frame_offset);
-    for xx in 0..(frame_offset + 1) {
-        while avcodec_receive_frame(avctx, out) == AVERROR(EAGAIN) {
-            avcodec_send_packet(avctx, inpkt);
-
-            loop {
-                av_packet_unref(inpkt);
-                av_read_frame(fctx, inpkt);
-                if (*inpkt).stream_index == our_shit.stream_index {
-                    break;
-                }
+//next packet, or the flush packet once the stream ended
+unsafe fn next_packet_or_drain(our_shit: &mut DecoderSetup) -> Result<()> {
+    match next_packet(our_shit) {
+        Err(Error::EndOfStream) => {
+            //makes the decoder return the frames it still holds back
+            let ret = avcodec_send_packet(our_shit.avctx, std::ptr::null());
+            if ret < 0 && ret != AVERROR_EOF {
+                return Err(Error::Ffmpeg {
+                    call: "avcodec_send_packet",
+                    code: ret,
+                });
             }
+            our_shit.draining = true;
+            Ok(())
+        }
+        e => e,
+    }
+}
+
+//HDR10 metadata, the HEVC decoder attaches it to every frame of the coded video sequence of the SEI.
+//It isn't carried on to sequences without one, that would depend on where the decoder started
+const STATIC_SIDE_DATA: [AVFrameSideDataType; 2] = [
+    AVFrameSideDataType::AV_FRAME_DATA_MASTERING_DISPLAY_METADATA,
+    AVFrameSideDataType::AV_FRAME_DATA_CONTENT_LIGHT_LEVEL,
+];
+
+fn q2d(q: AVRational) -> f64 {
+    if q.den == 0 {
+        0.0
+    } else {
+        q.num as f64 / q.den as f64
+    }
+}
+
+//CIE 1931 (x, y)
+type Chromaticity = (f64, f64);
+
+#[derive(Default)]
+pub struct HdrMetadata {
+    //red, green, blue and the white point
+    pub primaries: Option<([Chromaticity; 3], Chromaticity)>,
+    //cd/m², (min, max)
+    pub luminance: Option<(f64, f64)>,
+    //MaxCLL, MaxFALL
+    pub content_light_level: Option<(u32, u32)>,
+}
+
+pub unsafe fn hdr_metadata(frame: *const AVFrame) -> HdrMetadata {
+    let mut ret = HdrMetadata::default();
+
+    let sd = av_frame_get_side_data(frame, STATIC_SIDE_DATA[0]);
+    if !sd.is_null() {
+        let md = &*((*sd).data as *const AVMasteringDisplayMetadata);
+        if md.has_primaries != 0 {
+            let p = md.display_primaries;
+            ret.primaries = Some((
+                [
+                    (q2d(p[0][0]), q2d(p[0][1])),
+                    (q2d(p[1][0]), q2d(p[1][1])),
+                    (q2d(p[2][0]), q2d(p[2][1])),
+                ],
+                (q2d(md.white_point[0]), q2d(md.white_point[1])),
+            ));
+        }
+        if md.has_luminance != 0 {
+            ret.luminance = Some((q2d(md.min_luminance), q2d(md.max_luminance)));
+        }
+    }
+
+    let sd = av_frame_get_side_data(frame, STATIC_SIDE_DATA[1]);
+    if !sd.is_null() {
+        let cll = &*((*sd).data as *const AVContentLightMetadata);
+        ret.content_light_level = Some((cll.MaxCLL, cll.MaxFALL));
+    }
+    ret
+}
+
+//next decoded frame into `out`, false once the decoder is drained
+pub unsafe fn receive_frame(our_shit: &mut DecoderSetup, out: *mut AVFrame) -> Result<bool> {
+    //inpkt holds the next packet to send, empty on a new decoder
+    if !our_shit.draining && (*our_shit.inpkt).data.is_null() {
+        next_packet_or_drain(our_shit)?;
+    }
+    loop {
+        let ret = avcodec_receive_frame(our_shit.avctx, out);
+        if ret == 0 {
+            return Ok(true);
+        }
+        if ret == AVERROR_EOF {
+            return Ok(false);
+        }
+        if ret != AVERROR(EAGAIN) {
+            return Err(Error::Ffmpeg {
+                call: "avcodec_receive_frame",
+                code: ret,
+            });
+        }
+        if our_shit.draining {
+            return Ok(false);
+        }
+        let ret = avcodec_send_packet(our_shit.avctx, our_shit.inpkt);
+        //frames have to be received first, the packet stays and is sent again
+        if ret == AVERROR(EAGAIN) {
+            continue;
+        }
+        //a broken packet only costs its frames, the decoder carries on with the next one
+        if ret < 0 && ret != AVERROR_INVALIDDATA {
+            return Err(Error::Ffmpeg {
+                call: "avcodec_send_packet",
+                code: ret,
+            });
+        }
+        next_packet_or_drain(our_shit)?;
+    }
+}
+
+//`on_skipped` gets the offset and a reference of every frame decoded on the way
+pub unsafe fn read_nth_frame(
+    our_shit: &mut DecoderSetup,
+    frame_offset: u64,
+    mut on_skipped: impl FnMut(u64, AutoFreeFrame),
+) -> Result<AutoFreeFrame> {
+    let out = AutoFreeFrame {
+        frame: av_frame_alloc(),
+    };
+    for xx in 0..(frame_offset + 1) {
+        if !receive_frame(our_shit, out.frame)? {
+            return Err(Error::EndOfStream);
         }
         if xx != frame_offset {
-            av_frame_unref(out);
+            on_skipped(xx, out.new_ref());
+            av_frame_unref(out.frame);
         }
     }
-    AutoFreeFrame { frame: out }
+    Ok(out)
 }
 
 #[derive(Debug)]
 pub struct Analisys {
     pub width: u64,
     pub height: u64,
-    pub format: AVPixelFormat,
+    //None if it isn't one we can serve
+    pub format: Option<AVPixelFormat>,
+    pub raw_format: i32,
 
     pub last_packet_frame_cnt: u64,
+    //how long those frames are displayed, more than 2 per frame with pulldown (repeat_pict)
+    pub last_packet_half_frames: u64,
+    //any of those frames was coded as fields or a field pair
+    pub interlaced: bool,
 }
 
-pub unsafe fn analyse_end(our_shit: &mut DecoderSetup) -> Analisys {
-    use ffmpeg_sys::*;
-    let mut inpkt = av_packet_alloc();
-
-    let fctx = our_shit.fctx;
-    let avctx = our_shit.avctx;
-
-    av_read_frame(fctx, inpkt);
-
-    while (*inpkt).stream_index != our_shit.stream_index {
-        av_packet_unref(inpkt);
-        av_read_frame(fctx, inpkt);
-    }
+pub unsafe fn analyse_end(our_shit: &mut DecoderSetup) -> Result<Analisys> {
     let mut rett = Analisys {
         width: 0,
         height: 0,
-        format: AVPixelFormat::AV_PIX_FMT_YUV420P,
+        format: None,
+        raw_format: -1,
         last_packet_frame_cnt: 0,
+        last_packet_half_frames: 0,
+        interlaced: false,
     };
 
-    let mut out = av_frame_alloc();
-    'asd: loop {
-        while avcodec_receive_frame(avctx, out) == AVERROR(EAGAIN) {
-            avcodec_send_packet(avctx, inpkt);
-
-            loop {
-                av_packet_unref(inpkt);
-                let read_frame_ret = av_read_frame(fctx, inpkt);
-                if read_frame_ret != 0 {
-                    break 'asd;
-                }
-
-                if (*inpkt).stream_index == our_shit.stream_index {
-                    break;
-                }
-            }
-        }
+    let out = AutoFreeFrame {
+        frame: av_frame_alloc(),
+    };
+    //everything up to the end of the drained decoder, so the frame count is exact
+    while receive_frame(our_shit, out.frame)? {
+        let f = &*out.frame;
         if rett.last_packet_frame_cnt == 0 {
-            rett.width = (*out).width as u64;
-            rett.height = (*out).height as u64;
-            const FMT_420: i32 = AVPixelFormat::AV_PIX_FMT_YUV420P as i32;
-
-            rett.format = match (*out).format {
-                FMT_420 => AVPixelFormat::AV_PIX_FMT_YUV420P,
-                _ => panic!("Unsopported pixel fmt"),
-            };
+            rett.width = f.width as u64;
+            rett.height = f.height as u64;
+            rett.raw_format = f.format;
+            rett.format = super::pixfmt::from_raw(f.format);
         }
 
         rett.last_packet_frame_cnt += 1;
+        rett.last_packet_half_frames += 2 + f.repeat_pict.max(0) as u64;
+        rett.interlaced |= f.interlaced_frame != 0;
+        av_frame_unref(out.frame);
+    }
+    //nothing decodable after the last keyframe
+    if rett.last_packet_frame_cnt == 0 {
+        return Err(Error::EndOfStream);
     }
-    av_packet_free((&mut inpkt) as _);
-    av_frame_free((&mut out) as _);
-    rett
+    Ok(rett)
 }
 
 /*
@@ -0,0 +1,47 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::ffmpeg_stuff::AutoFreeFrame;
+
+//recently decoded frames by output frame number, references to the decoder's buffers
+//so stepping back inside a gop doesn't need to decode it again
+pub struct FrameCache {
+    //least recently used first
+    frames: Mutex<VecDeque<(u64, AutoFreeFrame)>>,
+    size: usize,
+}
+
+unsafe impl Send for FrameCache {}
+unsafe impl Sync for FrameCache {}
+
+impl FrameCache {
+    pub fn new(size: usize) -> FrameCache {
+        FrameCache {
+            frames: Mutex::new(VecDeque::with_capacity(size)),
+            size,
+        }
+    }
+
+    //a new reference to the cached frame
+    pub fn get(&self, n: u64) -> Option<AutoFreeFrame> {
+        let mut lck = self.frames.lock().unwrap();
+        let idx = lck.iter().position(|e| e.0 == n)?;
+        let entry = lck.remove(idx).unwrap();
+        let ret = entry.1.new_ref();
+        lck.push_back(entry);
+        Some(ret)
+    }
+
+    pub fn insert(&self, n: u64, frame: AutoFreeFrame) {
+        if self.size == 0 {
+            return;
+        }
+        let mut lck = self.frames.lock().unwrap();
+        if let Some(idx) = lck.iter().position(|e| e.0 == n) {
+            lck.remove(idx);
+        }
+        while lck.len() >= self.size {
+            lck.pop_front();
+        }
+        lck.push_back((n, frame));
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::error::{Error, Result};
+use crate::predict::{ExactIndex, PredictedKeyFrame};
+use crate::stream_source::StreamSource;
+
+const MAGIC: &[u8; 8] = b"BDNGSPIX";
+//2: frame counts include the frames drained from the decoder at the end
+//3: PAFF fields merged, coded frame rate of pulldown streams
+//4: interlacing from the decoded frames, pulldown always scanned
+const VERSION: u32 = 4;
+
+//everything Segment::open has to decode/scan for
+pub struct CachedIndex {
+    pub pred: Vec<PredictedKeyFrame>,
+    pub exact: Option<ExactIndex>,
+    pub width: u64,
+    pub height: u64,
+    pub raw_format: i32,
+    pub last_packet_frame_cnt: u64,
+    pub last_packet_half_frames: u64,
+    pub interlaced: bool,
+    pub frame_rate_n: u32,
+    pub frame_rate_d: u32,
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    if let Some(e) = std::env::var_os("BDNGSP_CACHE_DIR") {
+        return Some(PathBuf::from(e));
+    }
+    if let Some(e) = std::env::var_os("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(e).join("bdngsp"));
+    }
+    if let Some(e) = std::env::var_os("LOCALAPPDATA") {
+        return Some(PathBuf::from(e).join("bdngsp"));
+    }
+    std::env::var_os("HOME").map(|e| PathBuf::from(e).join(".cache").join("bdngsp"))
+}
+
+//the index is only valid for the same data (for files size and mtime) opened the same way
+fn cache_key(src: &dyn StreamSource, pid: u16, exact: bool) -> String {
+    format!("{}|{}|{}", src.id(), pid, exact)
+}
+
+//fnv-1a, std's hasher isn't stable between releases
+fn hash(s: &str) -> u64 {
+    let mut h = 0xcbf29ce484222325u64;
+    for b in s.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+fn cache_file(key: &str) -> Option<PathBuf> {
+    cache_dir().map(|e| e.join(format!("{:016x}.idx", hash(key))))
+}
+
+fn read_index(r: &mut impl Read, key: &str) -> Result<CachedIndex> {
+    let invalid = |e: &str| Error::InvalidIndex(e.to_owned());
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC || r.read_u32::<LE>()? != VERSION {
+        return Err(invalid("not a index file"));
+    }
+
+    //nothing read from the file is trusted with an allocation
+    let key_len = r.read_u32::<LE>()?;
+    if key_len as usize != key.len() {
+        return Err(invalid("index of a different file"));
+    }
+    let mut stored_key = vec![0u8; key.len()];
+    r.read_exact(&mut stored_key)?;
+    if stored_key != key.as_bytes() {
+        return Err(invalid("index of a different file"));
+    }
+
+    let width = r.read_u64::<LE>()?;
+    let height = r.read_u64::<LE>()?;
+    let raw_format = r.read_i32::<LE>()?;
+    let last_packet_frame_cnt = r.read_u64::<LE>()?;
+    let last_packet_half_frames = r.read_u64::<LE>()?;
+    let interlaced = r.read_u8()? != 0;
+    let frame_rate_n = r.read_u32::<LE>()?;
+    let frame_rate_d = r.read_u32::<LE>()?;
+    if frame_rate_n == 0 || frame_rate_d == 0 {
+        return Err(invalid("no frame rate"));
+    }
+
+    let num_pred = r.read_u64::<LE>()?;
+    let mut pred = Vec::new();
+    for _ in 0..num_pred {
+        pred.push(PredictedKeyFrame {
+            number: r.read_u64::<LE>()?,
+            pts: r.read_u64::<LE>()?,
+            spn: r.read_u32::<LE>()?,
+        });
+    }
+    if pred.is_empty() {
+        return Err(invalid("no keyframes"));
+    }
+
+    let exact = if r.read_u8()? != 0 {
+        Some(ExactIndex::read(r)?)
+    } else {
+        None
+    };
+
+    Ok(CachedIndex {
+        pred,
+        exact,
+        width,
+        height,
+        raw_format,
+        last_packet_frame_cnt,
+        last_packet_half_frames,
+        interlaced,
+        frame_rate_n,
+        frame_rate_d,
+    })
+}
+
+fn write_index(w: &mut impl Write, key: &str, idx: &CachedIndex) -> Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_u32::<LE>(VERSION)?;
+    w.write_u32::<LE>(key.len() as u32)?;
+    w.write_all(key.as_bytes())?;
+
+    w.write_u64::<LE>(idx.width)?;
+    w.write_u64::<LE>(idx.height)?;
+    w.write_i32::<LE>(idx.raw_format)?;
+    w.write_u64::<LE>(idx.last_packet_frame_cnt)?;
+    w.write_u64::<LE>(idx.last_packet_half_frames)?;
+    w.write_u8(idx.interlaced as u8)?;
+    w.write_u32::<LE>(idx.frame_rate_n)?;
+    w.write_u32::<LE>(idx.frame_rate_d)?;
+
+    w.write_u64::<LE>(idx.pred.len() as u64)?;
+    for kf in &idx.pred {
+        w.write_u64::<LE>(kf.number)?;
+        w.write_u64::<LE>(kf.pts)?;
+        w.write_u32::<LE>(kf.spn)?;
+    }
+
+    match &idx.exact {
+        Some(e) => {
+            w.write_u8(1)?;
+            e.write(w)?;
+        }
+        None => w.write_u8(0)?,
+    }
+    Ok(())
+}
+
+//None on any problem, the index just gets rebuilt then
+pub fn load(src: &dyn StreamSource, pid: u16, exact: bool) -> Option<CachedIndex> {
+    let key = cache_key(src, pid, exact);
+    let file = File::open(cache_file(&key)?).ok()?;
+    read_index(&mut BufReader::new(file), &key).ok()
+}
+
+pub fn store(src: &dyn StreamSource, pid: u16, exact: bool, idx: &CachedIndex) -> Result<()> {
+    let key = cache_key(src, pid, exact);
+    let path = match cache_file(&key) {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    //so a concurrent load never sees a half written file
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        write_index(&mut w, &key, idx)?;
+        w.flush()?;
+    }
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "/bd/00001.m2ts|1234|5678|4113|false";
+
+    fn index(exact: bool) -> CachedIndex {
+        CachedIndex {
+            pred: vec![
+                PredictedKeyFrame {
+                    number: 0,
+                    pts: 27_000,
+                    spn: 0,
+                },
+                PredictedKeyFrame {
+                    number: 24,
+                    pts: 72_045,
+                    spn: 3_072,
+                },
+            ],
+            exact: exact
+                .then(|| ExactIndex::new(&[(0, 54_000), (3_072, 144_090)], &[], (24000, 1001))),
+            width: 1920,
+            height: 1080,
+            raw_format: 0,
+            last_packet_frame_cnt: 12,
+            last_packet_half_frames: 30,
+            interlaced: true,
+            frame_rate_n: 30000,
+            frame_rate_d: 1001,
+        }
+    }
+
+    fn written(idx: &CachedIndex) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_index(&mut buf, KEY, idx).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        for exact in [false, true] {
+            let idx = read_index(&mut written(&index(exact)).as_slice(), KEY).unwrap();
+            assert_eq!((idx.width, idx.height, idx.raw_format), (1920, 1080, 0));
+            assert_eq!(
+                (idx.last_packet_frame_cnt, idx.last_packet_half_frames),
+                (12, 30)
+            );
+            assert!(idx.interlaced);
+            assert_eq!((idx.frame_rate_n, idx.frame_rate_d), (30000, 1001));
+            let pred: Vec<_> = idx.pred.iter().map(|e| (e.number, e.pts, e.spn)).collect();
+            assert_eq!(pred, [(0, 27_000, 0), (24, 72_045, 3_072)]);
+
+            assert_eq!(idx.exact.is_some(), exact);
+            if let Some(e) = idx.exact {
+                assert_eq!(e.keyframe_number(3_072, 72_045), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let buf = written(&index(false));
+        let other = KEY.replace("1234", "1235");
+        assert!(matches!(
+            read_index(&mut buf.as_slice(), &other),
+            Err(Error::InvalidIndex(_))
+        ));
+        assert!(read_index(&mut buf.as_slice(), "short").is_err());
+    }
+
+    #[test]
+    fn rejects_other_versions_and_damage() {
+        let mut buf = written(&index(false));
+        buf[8] = VERSION as u8 + 1;
+        assert!(matches!(
+            read_index(&mut buf.as_slice(), KEY),
+            Err(Error::InvalidIndex(_))
+        ));
+
+        let buf = written(&index(true));
+        assert!(read_index(&mut &buf[..buf.len() - 1], KEY).is_err());
+
+        let mut idx = index(false);
+        idx.frame_rate_d = 0;
+        assert!(matches!(
+            read_index(&mut written(&idx).as_slice(), KEY),
+            Err(Error::InvalidIndex(_))
+        ));
+    }
+
+    #[test]
+    fn stable_hash() {
+        //the file names have to stay the same between builds
+        assert_eq!(hash(""), 0xcbf29ce484222325);
+        assert_eq!(hash("a"), 0xaf63dc4c8601ec8c);
+    }
+}
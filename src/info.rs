@@ -0,0 +1,221 @@
+use anyhow::Error;
+use vapoursynth::core::CoreRef;
+use vapoursynth::node::Flags;
+use vapoursynth::plugins::{Filter, FrameContext};
+use vapoursynth::prelude::*;
+use vapoursynth::video_info::{Framerate, Resolution, VideoInfo};
+
+use crate::clpi::CLPIResult;
+use crate::m2ts::PacketInfo;
+
+pub enum Prop {
+    Int(i64),
+    Data(Vec<u8>),
+    IntArray(Vec<i64>),
+}
+
+pub type PropList = Vec<(&'static str, Prop)>;
+
+//Filter functions can't return plain maps, so the info is served as the props
+//of 1x1 Gray8 frames, one frame per record
+pub struct InfoFilter {
+    pub frames: Vec<PropList>,
+}
+
+impl<'core> Filter<'core> for InfoFilter {
+    fn video_info(&self, _api: API, core: CoreRef<'core>) -> Vec<VideoInfo<'core>> {
+        let info = VideoInfo {
+            format: core.get_format(PresetFormat::Gray8.into()).unwrap().into(),
+            framerate: Framerate {
+                numerator: 1,
+                denominator: 1,
+            }
+            .into(),
+            resolution: Resolution {
+                width: 1,
+                height: 1,
+            }
+            .into(),
+            num_frames: self.frames.len().into(),
+            flags: Flags::empty(),
+        };
+        vec![info]
+    }
+
+    fn get_frame_initial(
+        &self,
+        _api: API,
+        core: CoreRef<'core>,
+        _context: FrameContext,
+        n: usize,
+    ) -> Result<Option<FrameRef<'core>>, Error> {
+        let format = core.get_format(PresetFormat::Gray8.into()).unwrap();
+        let mut frame = unsafe {
+            FrameRefMut::new_uninitialized(
+                core,
+                None,
+                format,
+                Resolution {
+                    width: 1,
+                    height: 1,
+                },
+            )
+        };
+        frame.data_row_mut(0, 0)[0] = 0;
+
+        {
+            let mut props = frame.props_mut();
+            for (key, prop) in &self.frames[n] {
+                match prop {
+                    Prop::Int(v) => props.set_int(key, *v)?,
+                    Prop::Data(v) => props.set_data(key, v)?,
+                    Prop::IntArray(v) => {
+                        for e in v {
+                            props.append_int(key, *e)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Some(frame.into()))
+    }
+
+    fn get_frame(
+        &self,
+        _api: API,
+        _core: CoreRef<'core>,
+        _context: FrameContext,
+        _n: usize,
+    ) -> Result<FrameRef<'core>, Error> {
+        unreachable!()
+    }
+}
+
+pub fn clip_info_props(clpi: &CLPIResult) -> PropList {
+    let ci = &clpi.clip_info;
+    let mut props: PropList = vec![
+        (
+            "TypeIndicator",
+            Prop::Data(ci.type_indicator.as_bytes().to_vec()),
+        ),
+        ("Version", Prop::Data(ci.version.as_bytes().to_vec())),
+        ("ClipStreamType", Prop::Int(ci.clip_stream_type as _)),
+        ("ApplicationType", Prop::Int(ci.application_type as _)),
+        ("IsATCDelta", Prop::Int(ci.is_atc_delta as _)),
+        ("TSRecordingRate", Prop::Int(ci.ts_recording_rate as _)),
+        ("NumSourcePackets", Prop::Int(ci.num_source_packets as _)),
+        (
+            "FormatIdentifier",
+            Prop::Data(ci.format_identifier.as_bytes().to_vec()),
+        ),
+    ];
+
+    //empty arrays can't be stored, so those keys are left out
+    let mut push_array = |key: &'static str, v: Vec<i64>| {
+        if !v.is_empty() {
+            props.push((key, Prop::IntArray(v)));
+        }
+    };
+
+    let eps = &clpi.ep_streams;
+    push_array("EPStreamPID", eps.iter().map(|e| e.pid as _).collect());
+    push_array(
+        "EPStreamType",
+        eps.iter().map(|e| e.stream_type as _).collect(),
+    );
+    push_array(
+        "EPEntries",
+        eps.iter().map(|e| e.combined.len() as _).collect(),
+    );
+
+    let streams: Vec<_> = clpi
+        .programs
+        .iter()
+        .flat_map(|e| e.streams.iter())
+        .collect();
+    push_array("StreamPID", streams.iter().map(|e| e.pid as _).collect());
+    push_array(
+        "StreamCodingType",
+        streams.iter().map(|e| e.coding_type as _).collect(),
+    );
+    push_array(
+        "VideoFormat",
+        streams.iter().map(|e| e.video_format as _).collect(),
+    );
+    push_array(
+        "FrameRate",
+        streams.iter().map(|e| e.frame_rate as _).collect(),
+    );
+
+    let stcs = clpi.stc_sequences();
+    push_array("STCId", stcs.iter().map(|e| e.id as _).collect());
+    push_array(
+        "STCStartSPN",
+        stcs.iter().map(|e| e.spn_stc_start as _).collect(),
+    );
+    push_array(
+        "STCPresentationStart",
+        stcs.iter()
+            .map(|e| e.presentation_start_time as _)
+            .collect(),
+    );
+    push_array(
+        "STCPresentationEnd",
+        stcs.iter().map(|e| e.presentation_end_time as _).collect(),
+    );
+
+    let marks = &clpi.clip_marks;
+    push_array(
+        "ClipMarkType",
+        marks.iter().map(|e| e.mark_type as _).collect(),
+    );
+    push_array(
+        "ClipMarkSTCId",
+        marks.iter().map(|e| e.stc_id as _).collect(),
+    );
+    push_array(
+        "ClipMarkTime",
+        marks.iter().map(|e| e.time_stamp as _).collect(),
+    );
+    push_array(
+        "ClipMarkPID",
+        marks.iter().map(|e| e.entry_es_pid as _).collect(),
+    );
+    push_array(
+        "ClipMarkDuration",
+        marks.iter().map(|e| e.duration as _).collect(),
+    );
+
+    let ext = &clpi.extension_data;
+    push_array("ExtDataID1", ext.iter().map(|e| e.id1 as _).collect());
+    push_array("ExtDataID2", ext.iter().map(|e| e.id2 as _).collect());
+    push_array(
+        "ExtDataStart",
+        ext.iter().map(|e| e.start_address as _).collect(),
+    );
+    push_array("ExtDataLength", ext.iter().map(|e| e.length as _).collect());
+
+    props
+}
+
+pub fn packet_info_props(p: &PacketInfo) -> PropList {
+    let mut props: PropList = vec![
+        ("SPN", Prop::Int(p.spn as _)),
+        ("CopyPermission", Prop::Int(p.copy_permission as _)),
+        ("ATS", Prop::Int(p.ats as _)),
+        ("PID", Prop::Int(p.pid as _)),
+        ("PayloadUnitStart", Prop::Int(p.payload_unit_start as _)),
+    ];
+    //only the ones the packet has
+    if let Some(e) = p.pcr {
+        props.push(("PCR", Prop::Int(e as _)));
+    }
+    if let Some(e) = p.pts {
+        props.push(("PTS", Prop::Int(e as _)));
+    }
+    if let Some(e) = p.dts {
+        props.push(("DTS", Prop::Int(e as _)));
+    }
+    props
+}
@@ -1,44 +1,63 @@
 #[macro_use]
 extern crate vapoursynth;
 
-use std::ffi::CStr;
 use std::fs::File;
+use std::io::BufReader;
 use std::marker::PhantomData;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
 
-use anyhow::{bail, Error};
-use cached_decoder::CachedDecoder;
+use anyhow::{bail, Context, Error};
+use cached_decoder::{CachedDecoder, DecoderPool};
 use ffmpeg_sys::AVFrame;
+use frame_cache::FrameCache;
 use vapoursynth::core::CoreRef;
 use vapoursynth::format::FormatID;
+use vapoursynth::map::ValueIter;
 use vapoursynth::node::Flags;
 use vapoursynth::plugins::{Filter, FilterArgument, FrameContext, Metadata};
 use vapoursynth::prelude::*;
 use vapoursynth::video_info::{Framerate, Resolution, VideoInfo};
 
+mod audio;
 mod cached_decoder;
 mod clpi;
+mod error;
 mod fcache;
 mod ffmpeg_stuff;
+mod frame_cache;
+mod index_cache;
+mod info;
+mod m2ts;
+mod mpls;
+mod pixfmt;
 mod predict;
+mod segment;
+mod stream_source;
+mod udf;
+
+//per clip, the gops of a whole disc would not fit in memory
+const DEFAULT_CACHE_MB: i64 = 512;
+const MAX_CACHE_MB: i64 = 1 << 24;
+//decoders kept open at different positions for parallel/random access
+const DEFAULT_DECODERS: i64 = 4;
+//decoded frames kept for stepping backwards
+const DEFAULT_FRAME_CACHE: i64 = 16;
+//PacketInfo keeps the props of every packet in memory
+const MAX_PACKET_INFO: u32 = 1_000_000;
 
 struct VSSourceFilter<'core> {
-    clpi: crate::clpi::CLPIResult,
+    segments: Vec<segment::Segment>,
 
-    pred: Vec<crate::predict::PredictedKeyFrame>,
-    pred_inner: Arc<Mutex<fcache::FileCacheBackend>>,
-
-    cached_decoder: Arc<Mutex<Option<CachedDecoder>>>,
-    cached_decoder_info: RwLock<Option<CachedDecoder>>,
-
-    global_lock: Arc<Mutex<bool>>,
+    decoders: DecoderPool,
+    frames: FrameCache,
 
     resolution: Resolution,
     framerate: Framerate,
     format_id: FormatID,
 
     num_frames: usize,
+    //output frame -> the frames of its two fields, when the repeat flags are applied
+    rff_map: Option<Vec<(u64, u64)>>,
 
     a: PhantomData<&'core u8>,
 }
@@ -46,69 +65,246 @@ struct VSSourceFilter<'core> {
 unsafe impl<'core> Send for VSSourceFilter<'core> {}
 unsafe impl<'core> Sync for VSSourceFilter<'core> {}
 
-fn extract_framedata(av_frame: &AVFrame, frame: &mut FrameRefMut) {
-    unsafe {
-        let y = std::slice::from_raw_parts(
-            av_frame.data[0],
-            av_frame.linesize[0] as usize * av_frame.height as usize,
-        );
-        let c = std::slice::from_raw_parts(
-            av_frame.data[1],
-            av_frame.linesize[1] as usize * (av_frame.height / 2) as usize,
-        );
-        let b = std::slice::from_raw_parts(
-            av_frame.data[2],
-            av_frame.linesize[2] as usize * (av_frame.height / 2) as usize,
-        );
-        {
-            let vs_y: &mut [u8] = frame.plane_mut(0).unwrap();
-            vs_y.copy_from_slice(y);
-        }
-        {
-            let vs_c: &mut [u8] = frame.plane_mut(1).unwrap();
-            vs_c.copy_from_slice(c);
-        }
-        {
-            let vs_b: &mut [u8] = frame.plane_mut(2).unwrap();
-            vs_b.copy_from_slice(b);
+//rounds up, ffmpeg keeps the last chroma row/column of odd sized frames
+fn subsampled(size: usize, sub_sampling: u8) -> usize {
+    (size + (1 << sub_sampling) - 1) >> sub_sampling
+}
+
+//`field` Some(0) only copies the top field (even rows), Some(1) the bottom one
+fn extract_framedata(av_frame: &AVFrame, frame: &mut FrameRefMut, field: Option<usize>) {
+    let format = frame.format();
+    let bytes_per_sample = format.bytes_per_sample() as usize;
+
+    for plane in 0..format.plane_count() {
+        let (ssw, ssh) = if plane == 0 {
+            (0, 0)
+        } else {
+            (format.sub_sampling_w(), format.sub_sampling_h())
+        };
+
+        //the decoded frame may be bigger (odd sizes get cropped) or smaller
+        //(resolution changes midstream) than the vapoursynth one
+        let src_row_bytes = subsampled(av_frame.width as usize, ssw) * bytes_per_sample;
+        let src_rows = subsampled(av_frame.height as usize, ssh);
+        //can be negative for bottom up frames
+        let linesize = av_frame.linesize[plane] as isize;
+
+        for row in 0..frame.height(plane) {
+            if field.is_some_and(|e| row % 2 != e) {
+                continue;
+            }
+            let dst = frame.data_row_mut(plane, row);
+            if row >= src_rows || av_frame.data[plane].is_null() {
+                dst.fill(0);
+                continue;
+            }
+
+            let to_copy = dst.len().min(src_row_bytes);
+            let src = unsafe {
+                std::slice::from_raw_parts(
+                    av_frame.data[plane].offset(row as isize * linesize),
+                    to_copy,
+                )
+            };
+            dst[..to_copy].copy_from_slice(src);
+            dst[to_copy..].fill(0);
         }
     }
 }
 
 impl<'core> VSSourceFilter<'core> {
+    fn segment_for(&self, n: u64) -> usize {
+        self.segments
+            .iter()
+            .rposition(|e| e.start <= n)
+            .unwrap_or(0)
+    }
+
+    //with `second` the first field comes from `raw_av_frame` and the second from `second`,
+    //`n` is the output frame and `coded` the decoded one
     fn avframe_to_vsframe(
         &self,
         core: CoreRef<'core>,
+        n: usize,
+        coded: u64,
         raw_av_frame: *mut AVFrame,
-    ) -> FrameRefMut<'core> {
+        second: Option<*mut AVFrame>,
+    ) -> Result<FrameRefMut<'core>, Error> {
         unsafe {
             let av_frame = &(*raw_av_frame);
 
             //println!("data {:?}", av_frame.data);
             //println!("linesz {:?}", av_frame.linesize);
 
-            let format = core.get_format(self.format_id).unwrap();
+            let format = match core.get_format(self.format_id) {
+                Some(e) => e,
+                None => bail!("Unknown format {:?}", self.format_id),
+            };
             let mut frame = FrameRefMut::new_uninitialized(core, None, format, self.resolution);
 
-            extract_framedata(av_frame, &mut frame);
+            //0 progressive, 1 bottom field first, 2 top field first. A woven frame also gets
+            //the field the other props come from as `_Field`, 0 bottom, 1 top
+            let (field_based, field) = match second {
+                Some(second) => {
+                    let second = &(*second);
+                    //the second frame starts with its first field
+                    let second_field = if second.top_field_first != 0 { 0 } else { 1 };
+                    extract_framedata(av_frame, &mut frame, Some(1 - second_field));
+                    extract_framedata(second, &mut frame, Some(second_field));
+                    if second_field == 0 {
+                        (1, Some(0))
+                    } else {
+                        (2, Some(1))
+                    }
+                }
+                None => {
+                    extract_framedata(av_frame, &mut frame, None);
+                    if av_frame.interlaced_frame == 0 {
+                        (0, None)
+                    } else if av_frame.top_field_first != 0 {
+                        (2, None)
+                    } else {
+                        (1, None)
+                    }
+                }
+            };
             {
                 let mut props = frame.props_mut();
                 match av_frame.pict_type {
-                    ffmpeg_sys::AVPictureType::AV_PICTURE_TYPE_I => props
-                        .append_data("_PictType", "I".to_owned().as_bytes())
-                        .unwrap(),
-                    ffmpeg_sys::AVPictureType::AV_PICTURE_TYPE_P => props
-                        .append_data("_PictType", "P".to_owned().as_bytes())
-                        .unwrap(),
-                    ffmpeg_sys::AVPictureType::AV_PICTURE_TYPE_B => props
-                        .append_data("_PictType", "B".to_owned().as_bytes())
-                        .unwrap(),
+                    ffmpeg_sys::AVPictureType::AV_PICTURE_TYPE_I => {
+                        props.append_data("_PictType", "I".to_owned().as_bytes())?
+                    }
+                    ffmpeg_sys::AVPictureType::AV_PICTURE_TYPE_P => {
+                        props.append_data("_PictType", "P".to_owned().as_bytes())?
+                    }
+                    ffmpeg_sys::AVPictureType::AV_PICTURE_TYPE_B => {
+                        props.append_data("_PictType", "B".to_owned().as_bytes())?
+                    }
 
                     _ => {}
                 };
+                props.append_int("_FieldBased", field_based)?;
+                if let Some(field) = field {
+                    props.append_int("_Field", field)?;
+                }
+
+                let duration = (self.framerate.denominator, self.framerate.numerator);
+                props.append_float(
+                    "_AbsoluteTime",
+                    n as f64 * duration.0 as f64 / duration.1 as f64,
+                )?;
+                props.append_int("_DurationNum", duration.0 as _)?;
+                props.append_int("_DurationDen", duration.1 as _)?;
+                props.append_int("_Key", av_frame.key_frame as _)?;
+                for (key, value) in pixfmt::color_props(av_frame) {
+                    props.append_int(key, value)?;
+                }
+
+                let hdr = ffmpeg_stuff::hdr_metadata(raw_av_frame);
+                if let Some((primaries, white_point)) = hdr.primaries {
+                    for (x, y) in primaries {
+                        props.append_float("MasteringDisplayPrimariesX", x)?;
+                        props.append_float("MasteringDisplayPrimariesY", y)?;
+                    }
+                    props.append_float("MasteringDisplayWhitePointX", white_point.0)?;
+                    props.append_float("MasteringDisplayWhitePointY", white_point.1)?;
+                }
+                if let Some((min, max)) = hdr.luminance {
+                    props.append_float("MasteringDisplayMinLuminance", min)?;
+                    props.append_float("MasteringDisplayMaxLuminance", max)?;
+                }
+                if let Some((max, average)) = hdr.content_light_level {
+                    props.append_int("ContentLightLevelMax", max as _)?;
+                    props.append_int("ContentLightLevelAverage", average as _)?;
+                }
+
+                //90khz, as in the m2ts. best_effort_timestamp would make one up for frames without
+                if av_frame.pts != ffmpeg_sys::AV_NOPTS_VALUE {
+                    props.append_int("BdPts", av_frame.pts)?;
+                }
+                if av_frame.pkt_dts != ffmpeg_sys::AV_NOPTS_VALUE {
+                    props.append_int("BdDts", av_frame.pkt_dts)?;
+                }
+                //the EP map entry the gop starts at
+                let seg = &self.segments[self.segment_for(coded)];
+                let local = coded - seg.start + seg.in_frame;
+                let (gop, _) = predict::get_frame_dump_info(local, &seg.pred)?;
+                props.append_int("BdGop", gop as _)?;
+                props.append_int("BdSpn", seg.pred[gop].spn as _)?;
             }
 
-            frame
+            Ok(frame)
+        }
+    }
+
+    //decoded frame `n` of the segments, without the repeat flags applied
+    fn decode_frame(&self, n: u64) -> Result<ffmpeg_stuff::AutoFreeFrame, Error> {
+        unsafe {
+            let seg = &self.segments[self.segment_for(n)];
+            let local = n - seg.start + seg.in_frame;
+            let ret = predict::get_frame_dump_info(local, &seg.pred)?;
+
+            if let Some(av_frame) = self.frames.get(n) {
+                return Ok(av_frame);
+            }
+
+            if let Some(d) = self.decoders.find(n) {
+                let mut dec = d.lock().unwrap();
+                //another thread could have moved it past n while we waited
+                if dec.can_serve(n) {
+                    let frame_offset = n - dec.current_idx;
+                    let first = dec.current_idx;
+
+                    let av_frame =
+                        ffmpeg_stuff::read_nth_frame(&mut dec.decoder, frame_offset, |i, f| {
+                            self.frames.insert(first + i, f)
+                        });
+                    let av_frame = match av_frame {
+                        Ok(e) => e,
+                        Err(e) => {
+                            //position is unknown now, don't use it again
+                            dec.frames_left = 0;
+                            self.decoders.update(&d, &dec);
+                            return Err(e).with_context(|| format!("Failed to decode frame {}", n));
+                        }
+                    };
+                    self.frames.insert(n, av_frame.new_ref());
+
+                    dec.current_idx += frame_offset + 1;
+                    dec.frames_left -= frame_offset + 1;
+                    self.decoders.update(&d, &dec);
+                    return Ok(av_frame);
+                }
+            }
+
+            let mut new_decoder = ffmpeg_stuff::setup_decoder(
+                fcache::FCached::new(seg.backend.clone()),
+                seg.pred[ret.0].spn,
+                seg.pid,
+                seg.codec_id,
+            )
+            .with_context(|| format!("Failed to start decoding frame {}", n))?;
+
+            //the gop can start before the segment when it is trimmed
+            let first = n as i64 - ret.1 as i64;
+            let av_frame = ffmpeg_stuff::read_nth_frame(&mut new_decoder, ret.1, |i, f| {
+                if first + i as i64 >= seg.start as i64 {
+                    self.frames.insert((first + i as i64) as u64, f)
+                }
+            })
+            .with_context(|| format!("Failed to decode frame {}", n))?;
+            self.frames.insert(n, av_frame.new_ref());
+
+            let gop_end = if ret.0 == seg.pred.len() - 1 {
+                seg.clip_frames
+            } else {
+                seg.pred[ret.0 + 1].number
+            };
+            let gop_end = gop_end.min(seg.in_frame + seg.num_frames);
+            self.decoders
+                .insert(CachedDecoder::new(new_decoder, n + 1, gop_end - local - 1));
+
+            Ok(av_frame)
         }
     }
 }
@@ -132,101 +328,20 @@ impl<'core> Filter<'core> for VSSourceFilter<'core> {
         _context: FrameContext,
         n: usize,
     ) -> Result<Option<FrameRef<'core>>, Error> {
-        unsafe {
-            //force singlethreadeness
-            let mut a = self.global_lock.lock().unwrap();
-            *a = !*a;
-
-            let ret = predict::get_frame_dump_info(n as _, &self.pred);
-
-            if let Some(c) = {
-                //this is extraordinarily stupid, why do i have to do this
-                let asd = self.cached_decoder_info.read().unwrap();
-                let r = asd.clone();
-                drop(asd);
-                r
-            } {
-                let n = n as u64;
-
-                let cache_end = c.current_idx + c.frames_left;
-                if n >= c.current_idx && n < cache_end {
-                    let mut lck = self.cached_decoder.lock().unwrap();
-                    let lll = lck.as_mut().unwrap();
-                    let d = lll.decoder.as_mut().unwrap();
-                    let mut d = d.lock().unwrap();
-
-                    let frame_offset = n - c.current_idx;
-
-                    //let old = std::time::Instant::now();
-                    let av_frame = ffmpeg_stuff::read_nth_frame(&mut d, frame_offset);
-
-                    let frame = self.avframe_to_vsframe(core, av_frame.frame);
-
-                    //let now = std::time::Instant::now();
-                    //println!("caacahe frame {}", (now - old).as_millis());
-
-                    let frames_getted = frame_offset + 1;
-
-                    //println!("ge {}", frames_getted);
-                    //update
-                    if (lll.frames_left as i64 - frames_getted as i64) <= 0 {
-                        *self.cached_decoder_info.write().unwrap() = None;
-                        drop(d);
-                        drop(lll);
-                        *lck = None;
-                    } else {
-                        lll.current_idx += frames_getted;
-                        lll.frames_left -= frames_getted;
-                        let mut lck = self.cached_decoder_info.write().unwrap();
-                        let cc = lck.as_mut().unwrap();
-                        cc.current_idx = lll.current_idx;
-                        cc.frames_left = lll.frames_left;
-                    }
-                    //println!("Return");
-                    return Ok(Some(frame.into()));
-                }
-            }
-
-            //let old = std::time::Instant::now();
-            let mut new_decoder = ffmpeg_stuff::setup_decoder(
-                Arc::new(Mutex::new(fcache::FCached::new(self.pred_inner.clone()))),
-                self.pred[ret.0].spn,
-                self.clpi.stream_pid,
-            )
-            .unwrap();
-
-            // let now = std::time::Instant::now();
-            // println!("decoder setup took {}", (now - old).as_millis());
-            let av_frame = ffmpeg_stuff::read_nth_frame(&mut new_decoder, ret.1);
-            let frame = self.avframe_to_vsframe(core, av_frame.frame);
-
-            // let now = std::time::Instant::now();
-            // println!("frame took {}", (now - old).as_millis());
-
-            let mut lck = self.cached_decoder.lock().unwrap();
-
-            let aaa = if ret.0 == self.pred.len() {
-                self.num_frames as u64 - self.pred[ret.0].number - 1
-            } else {
-                self.pred[ret.0 + 1].number - self.pred[ret.0].number - 1
-            };
-            let chc = CachedDecoder::new(
-                new_decoder,
-                self.pred[ret.0].number,
-                self.pred[ret.0].number + ret.1 + 1,
-                aaa - ret.1,
-            );
-            let mut w = self.cached_decoder_info.write().unwrap();
-
-            *w = Some(CachedDecoder {
-                decoder: None,
-                ..chc.clone()
-            });
-            *lck = Some(chc);
-
-            Ok(Some(frame.into()))
+        let (first, second) = match &self.rff_map {
+            Some(e) => e[n],
+            None => (n as u64, n as u64),
+        };
+        let av_frame = self.decode_frame(first)?;
+        if first == second {
+            return Ok(Some(
+                self.avframe_to_vsframe(core, n, first, av_frame.frame, None)?
+                    .into(),
+            ));
         }
-        //     self.source.request_frame_filter(context, n);
+        let second = self.decode_frame(second)?;
+        let frame = self.avframe_to_vsframe(core, n, first, av_frame.frame, Some(second.frame))?;
+        Ok(Some(frame.into()))
     }
 
     fn get_frame(
@@ -240,65 +355,252 @@ impl<'core> Filter<'core> for VSSourceFilter<'core> {
     }
 }
 
+//0 leaves the cache unbounded
+fn cache_bytes_arg(cache_mb: Option<i64>) -> Result<u64, Error> {
+    let cache_mb = cache_mb.unwrap_or(DEFAULT_CACHE_MB);
+    if !(0..=MAX_CACHE_MB).contains(&cache_mb) {
+        bail!("cache_mb must be between 0 and {}", MAX_CACHE_MB);
+    }
+    Ok((cache_mb as u64).saturating_mul(1024 * 1024))
+}
+
+//an out of range pid must not wrap around to another stream
+fn pid_arg(name: &str, pid: Option<i64>) -> Result<Option<u16>, Error> {
+    pid.map(|e| u16::try_from(e).with_context(|| format!("Invalid {}: {}", name, e)))
+        .transpose()
+}
+
+//a single path, or the parts of a m2ts split into several files
+fn path_args<'map>(paths: ValueIter<'map, '_, &'map [u8]>) -> Result<Vec<PathBuf>, Error> {
+    let paths = paths
+        .map(|e| Ok(PathBuf::from(std::str::from_utf8(e)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    if paths.is_empty() {
+        bail!("No path given");
+    }
+    Ok(paths)
+}
+
+//without clip/playlist `paths` is a m2ts (or its parts) or mpls, with them a disc image or folder
+fn open_segments(
+    paths: &[PathBuf],
+    clip: Option<&[u8]>,
+    playlist: Option<&[u8]>,
+    opts: &segment::OpenOptions,
+) -> Result<Vec<segment::Segment>, Error> {
+    if clip.is_none() && playlist.is_none() {
+        return segment::open_path(paths, opts);
+    }
+    if paths.len() != 1 {
+        bail!("A disc is opened from a single path");
+    }
+    let clip = clip.map(std::str::from_utf8).transpose()?;
+    let playlist = playlist.map(std::str::from_utf8).transpose()?;
+    segment::open_disc(&paths[0], clip, playlist, opts)
+}
+
 make_filter_function! {
     SourceFunction, "Source"
 
+    //every argument is a filter parameter
+    #[allow(clippy::too_many_arguments)]
     fn create_passthrough<'core>(
         _api: API,
-        _core: CoreRef<'core>,
-        name: &[u8],
+        core: CoreRef<'core>,
+        name: ValueIter<'_, 'core, &[u8]>,
+        pid: Option<i64>,
+        exact: Option<i64>,
+        cache: Option<i64>,
+        cache_mb: Option<i64>,
+        mmap: Option<i64>,
+        clip: Option<&[u8]>,
+        playlist: Option<&[u8]>,
+        decoders: Option<i64>,
+        frame_cache: Option<i64>,
+        rff: Option<i64>,
     ) -> Result<Option<Box<dyn Filter<'core> + 'core>>, Error> {
-        let name = unsafe { CStr::from_ptr(name.as_ptr() as _) };
+        let rff = rff.unwrap_or(0) != 0;
+        let opts = segment::OpenOptions {
+            pid: pid_arg("pid", pid)?,
+            //the repeat flags of every frame are only known from the scan
+            exact: exact.unwrap_or(0) != 0 || rff,
+            cache: cache.unwrap_or(1) != 0,
+            cache_bytes: cache_bytes_arg(cache_mb)?,
+            mmap: mmap.unwrap_or(0) != 0,
+        };
 
-        let stream_path = PathBuf::from(name.to_str().unwrap());
-        if !stream_path.exists() {
-            bail!("Stream does not exists");
-        }
-        let stream_idx_number = stream_path.file_stem().unwrap();
+        let paths = path_args(name)?;
+        let segments = open_segments(&paths, clip, playlist, &opts)?;
 
-        let clip_info = stream_path.parent().unwrap().parent().unwrap().join("CLIPINF").join(&format!("{}.clpi",stream_idx_number.to_str().unwrap()));
+        let first = &segments[0];
+        let last = &segments[segments.len() - 1];
+
+        //vapoursynth wants the size to be a multiple of the subsampling, odd sizes lose their last row/column
+        let format = match core.get_format(first.vs_format.into()) {
+            Some(e) => e,
+            None => bail!("Vapoursynth doesn't know {:?}", first.vs_format),
+        };
+        let width = first.analisys.width as usize & !((1 << format.sub_sampling_w()) - 1);
+        let height = first.analisys.height as usize & !((1 << format.sub_sampling_h()) - 1);
+
+        let (rff_map, framerate, num_frames) = if rff {
+            let map = segment::rff_frame_map(&segments);
+            let rate = predict::clip_frame_rate(&first.clpi, first.pid, first.analisys.interlaced);
+            let num_frames = map.len();
+            (Some(map), rate, num_frames)
+        } else {
+            let rate = (first.frame_rate_n, first.frame_rate_d);
+            (None, rate, (last.start + last.num_frames) as usize)
+        };
+
+        Ok(Some(Box::new(VSSourceFilter {
+            framerate: Framerate {
+                numerator: framerate.0 as _,
+                denominator: framerate.1 as _,
+            },
+            resolution: Resolution {
+                width,
+                height,
+            },
+            num_frames,
+            rff_map,
+            format_id: first.vs_format.into(),
+            segments,
+            a: Default::default(),
+            decoders: DecoderPool::new(decoders.unwrap_or(DEFAULT_DECODERS).max(1) as usize),
+            frames: FrameCache::new(frame_cache.unwrap_or(DEFAULT_FRAME_CACHE).max(0) as usize),
+        })))
+    }
+}
+
+make_filter_function! {
+    ClipInfoFunction, "ClipInfo"
+
+    fn create_clip_info<'core>(
+        _api: API,
+        _core: CoreRef<'core>,
+        path: &[u8],
+    ) -> Result<Option<Box<dyn Filter<'core> + 'core>>, Error> {
+        let path = PathBuf::from(std::str::from_utf8(path)?);
+
+        //the m2ts can be passed as well
+        let clip_info = match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("clpi") => path,
+            _ => segment::clip_info_for_stream(&path)?,
+        };
         if !clip_info.exists() {
             bail!("CLIPINFO does not exists");
         }
 
-        let clpi = clpi::read_clpi(&mut (File::open(clip_info)?)).unwrap();
-        let pred = predict::predict_frame_numbers(&clpi.combined);
+        let clpi = match clpi::read_clpi(&mut (File::open(clip_info)?)) {
+            Ok(e) => e,
+            Err(e) => bail!("Failed to read clip info: {}", e),
+        };
 
-        let inner = Arc::new(Mutex::new(fcache::FileCacheBackend::new(File::open(&stream_path).unwrap(),&pred)));
+        Ok(Some(Box::new(info::InfoFilter {
+            frames: vec![info::clip_info_props(&clpi)],
+        })))
+    }
+}
 
-        let caached = fcache::FCached::new(inner.clone());
-        let caached = Arc::new(Mutex::new(caached));
+make_filter_function! {
+    PacketInfoFunction, "PacketInfo"
 
-        //Get format and end frames
-        let (setup,framecnt) = unsafe {
-            let mut setup = ffmpeg_stuff::setup_decoder(caached.clone(),pred[pred.len()-1].spn,clpi.stream_pid).unwrap();
-            let framecnt = ffmpeg_stuff::analyse_end(&mut setup);
-            (setup,framecnt)
+    fn create_packet_info<'core>(
+        _api: API,
+        _core: CoreRef<'core>,
+        path: &[u8],
+        start_spn: i64,
+        count: i64,
+        pid: Option<i64>,
+        clip: Option<&[u8]>,
+    ) -> Result<Option<Box<dyn Filter<'core> + 'core>>, Error> {
+        let start_spn =
+            u32::try_from(start_spn).with_context(|| format!("Invalid start_spn: {}", start_spn))?;
+        let count = u32::try_from(count).with_context(|| format!("Invalid count: {}", count))?;
+        if count > MAX_PACKET_INFO {
+            bail!("count must be at most {}", MAX_PACKET_INFO);
+        }
+        let path = PathBuf::from(std::str::from_utf8(path)?);
+        //the m2ts itself, or the clip of a disc like Source
+        let stream = match clip {
+            Some(clip) => segment::Bdmv::open(&path, false)?
+                .open_clip_stream(std::str::from_utf8(clip)?, false)?,
+            None => stream_source::open_files(&[path], false)?,
         };
+        let mut f = BufReader::new(stream_source::SourceReader::new(stream));
+
+        let packets = m2ts::walk_packets(
+            &mut f,
+            start_spn,
+            count,
+            pid_arg("pid", pid)?,
+        )?;
+        if packets.is_empty() {
+            bail!("No source packets in the range");
+        }
 
-
-        Ok(Some(Box::new(VSSourceFilter {
-            framerate: Framerate {
-                numerator: setup.frame_rate_n as _,
-                denominator: setup.frame_rate_d as _,
-            },
-            resolution: Resolution {
-                width: framecnt.width as usize,
-                height: framecnt.height as usize
-            }.into(),
-            num_frames: pred[pred.len()-1].number as usize + framecnt.last_packet_frame_cnt as usize,
-            format_id: PresetFormat::YUV420P8.into(),
-            pred_inner: inner,
-            pred: pred,
-            a: Default::default(),
-            clpi: clpi,
-            cached_decoder:      Arc::new(Mutex::new(None)),
-            cached_decoder_info: RwLock::new(None),
-            global_lock: Arc::new(Mutex::new(false)),
+        Ok(Some(Box::new(info::InfoFilter {
+            frames: packets.iter().map(info::packet_info_props).collect(),
         })))
     }
 }
 
+make_filter_function! {
+    DumpAudioFunction, "DumpAudio"
+
+    //every argument is a filter parameter
+    #[allow(clippy::too_many_arguments)]
+    fn create_dump_audio<'core>(
+        _api: API,
+        _core: CoreRef<'core>,
+        source: ValueIter<'_, 'core, &[u8]>,
+        output: &[u8],
+        audio_pid: Option<i64>,
+        first: Option<i64>,
+        last: Option<i64>,
+        pid: Option<i64>,
+        exact: Option<i64>,
+        cache: Option<i64>,
+        cache_mb: Option<i64>,
+        mmap: Option<i64>,
+        clip: Option<&[u8]>,
+        playlist: Option<&[u8]>,
+    ) -> Result<Option<Box<dyn Filter<'core> + 'core>>, Error> {
+        let source = path_args(source)?;
+        let output = PathBuf::from(std::str::from_utf8(output)?);
+
+        let opts = segment::OpenOptions {
+            pid: pid_arg("pid", pid)?,
+            exact: exact.unwrap_or(0) != 0,
+            cache: cache.unwrap_or(1) != 0,
+            cache_bytes: cache_bytes_arg(cache_mb)?,
+            mmap: mmap.unwrap_or(0) != 0,
+        };
+        let segments = open_segments(&source, clip, playlist, &opts)?;
+        let last_seg = &segments[segments.len() - 1];
+        let num_frames = last_seg.start + last_seg.num_frames;
+
+        let first = first.unwrap_or(0).max(0) as u64;
+        let last = match last {
+            Some(e) if e < 0 => bail!("last must not be negative"),
+            Some(e) => (e as u64).min(num_frames - 1),
+            None => num_frames - 1,
+        };
+        if first > last {
+            bail!("first must not be after last");
+        }
+
+        let audio_pid = pid_arg("audio_pid", audio_pid)?;
+        if let Err(e) = audio::dump_audio(&segments, audio_pid, first, last, &output) {
+            bail!("Failed to dump audio: {}", e);
+        }
+
+        //nothing to return, the wav is the result
+        Ok(None)
+    }
+}
+
 export_vapoursynth_plugin! {
     Metadata {
         identifier: "com.example.bdngsp",
@@ -306,5 +608,10 @@ export_vapoursynth_plugin! {
         name: "BD source Not Good but should Suffice for Preview",
         read_only: true,
     },
-    [SourceFunction::new()]
+    [
+        SourceFunction::new(),
+        ClipInfoFunction::new(),
+        PacketInfoFunction::new(),
+        DumpAudioFunction::new(),
+    ]
 }
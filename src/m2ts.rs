@@ -0,0 +1,739 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+//4 byte TP_extra_header + 188 byte transport packet
+pub const SOURCE_PACKET_SIZE: usize = 192;
+
+pub struct TsPacket<'a> {
+    pub pid: u16,
+    pub transport_error: bool,
+    pub payload_unit_start: bool,
+    //only counts packets with a payload
+    pub continuity_counter: Option<u8>,
+    //27mhz
+    pub pcr: Option<u64>,
+    pub payload: &'a [u8],
+}
+
+//TP_extra_header (copy_permission_indicator, arrival_time_stamp)
+pub fn parse_extra_header(sp: &[u8]) -> (u8, u32) {
+    let v = u32::from_be_bytes([sp[0], sp[1], sp[2], sp[3]]);
+    ((v >> 30) as u8, v & 0x3FFF_FFFF)
+}
+
+fn parse_pcr(b: &[u8]) -> u64 {
+    let base = (b[0] as u64) << 25
+        | (b[1] as u64) << 17
+        | (b[2] as u64) << 9
+        | (b[3] as u64) << 1
+        | (b[4] >> 7) as u64;
+    let ext = ((b[4] as u64 & 0x01) << 8) | b[5] as u64;
+    base * 300 + ext
+}
+
+//sp is a whole source packet
+pub fn parse_ts_packet(sp: &[u8]) -> Option<TsPacket<'_>> {
+    let ts = &sp[4..SOURCE_PACKET_SIZE];
+    if ts[0] != 0x47 {
+        return None;
+    }
+    let transport_error = ts[1] & 0x80 != 0;
+    let payload_unit_start = ts[1] & 0x40 != 0;
+    let pid = ((ts[1] as u16 & 0x1F) << 8) | ts[2] as u16;
+    let adaptation_field_control = (ts[3] >> 4) & 0x03;
+    let continuity_counter = if adaptation_field_control & 0x01 != 0 {
+        Some(ts[3] & 0x0F)
+    } else {
+        None
+    };
+
+    let pcr = if adaptation_field_control & 0x02 != 0 && ts[4] >= 7 && ts[5] & 0x10 != 0 {
+        Some(parse_pcr(&ts[6..12]))
+    } else {
+        None
+    };
+
+    let payload = match adaptation_field_control {
+        0x01 => &ts[4..],
+        0x03 => {
+            let start = 5 + ts[4] as usize;
+            if start > ts.len() {
+                return None;
+            }
+            &ts[start..]
+        }
+        //no payload
+        _ => &ts[ts.len()..],
+    };
+
+    Some(TsPacket {
+        pid,
+        transport_error,
+        payload_unit_start,
+        continuity_counter,
+        pcr,
+        payload,
+    })
+}
+
+fn parse_timestamp(b: &[u8]) -> u64 {
+    (((b[0] >> 1) & 0x07) as u64) << 30
+        | (b[1] as u64) << 22
+        | ((b[2] >> 1) as u64) << 15
+        | (b[3] as u64) << 7
+        | (b[4] >> 1) as u64
+}
+
+//90khz (pts, dts) from the start of a PES packet
+pub fn parse_pes_timestamps(payload: &[u8]) -> Option<(Option<u64>, Option<u64>)> {
+    if payload.len() < 9 || payload[0..3] != [0, 0, 1] {
+        return None;
+    }
+    //no optional header for padding/private_stream_2 etc.
+    if payload[6] & 0xC0 != 0x80 {
+        return Some((None, None));
+    }
+    let pts_dts_flags = payload[7] >> 6;
+
+    let pts = if pts_dts_flags & 0x02 != 0 && payload.len() >= 14 {
+        Some(parse_timestamp(&payload[9..14]))
+    } else {
+        None
+    };
+    let dts = if pts_dts_flags == 0x03 && payload.len() >= 19 {
+        Some(parse_timestamp(&payload[14..19]))
+    } else {
+        None
+    };
+    Some((pts, dts))
+}
+
+//a whole PSI section of a payload that starts one, sections spanning packets aren't
+//needed as the PAT/PMT of a BD fit in one
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let start = 1 + *payload.first()? as usize;
+    let sec = payload.get(start..)?;
+    if sec.len() < 3 {
+        return None;
+    }
+    let len = 3 + (((sec[1] as usize & 0x0F) << 8) | sec[2] as usize);
+    //header + CRC
+    if len < 12 || len > sec.len() {
+        return None;
+    }
+    Some(&sec[..len])
+}
+
+fn read_pid(b: &[u8]) -> u16 {
+    ((b[0] as u16 & 0x1F) << 8) | b[1] as u16
+}
+
+//PMT pid of the first program
+fn parse_pat(sec: &[u8]) -> Option<u16> {
+    if sec[0] != 0x00 {
+        return None;
+    }
+    sec[8..sec.len() - 4]
+        .chunks_exact(4)
+        .find(|e| e[0] != 0 || e[1] != 0)
+        .map(|e| read_pid(&e[2..]))
+}
+
+//(pid, stream_type) of every elementary stream
+fn parse_pmt(sec: &[u8]) -> Option<Vec<(u16, u8)>> {
+    if sec[0] != 0x02 || sec.len() < 16 {
+        return None;
+    }
+    let end = sec.len() - 4;
+    let program_info_length = ((sec[10] as usize & 0x0F) << 8) | sec[11] as usize;
+    let mut p = 12 + program_info_length;
+    let mut streams = Vec::new();
+    while p + 5 <= end {
+        let stream_type = sec[p];
+        let pid = read_pid(&sec[p + 1..]);
+        let es_info_length = ((sec[p + 3] as usize & 0x0F) << 8) | sec[p + 4] as usize;
+        streams.push((pid, stream_type));
+        p += 5 + es_info_length;
+    }
+    Some(streams)
+}
+
+//stream_id_extension of the PES_extension_2, TrueHD and its AC-3 core share a pid with different ones
+fn stream_id_extension(h: &[u8]) -> Option<u8> {
+    let flags = h[7];
+    if flags & 0x01 == 0 {
+        return None;
+    }
+    let mut p = 9;
+    //PTS, DTS, ESCR, ES_rate, DSM_trick_mode, additional_copy_info, PES_CRC
+    for (bit, len) in [
+        (0x80, 5),
+        (0x40, 5),
+        (0x20, 6),
+        (0x10, 3),
+        (0x08, 1),
+        (0x04, 1),
+        (0x02, 2),
+    ] {
+        if flags & bit != 0 {
+            p += len;
+        }
+    }
+    let ext = *h.get(p)?;
+    p += 1;
+    //private data, pack header, sequence counter, P-STD buffer
+    if ext & 0x80 != 0 {
+        p += 16;
+    }
+    if ext & 0x40 != 0 {
+        p += 1 + *h.get(p)? as usize;
+    }
+    if ext & 0x20 != 0 {
+        p += 2;
+    }
+    if ext & 0x10 != 0 {
+        p += 2;
+    }
+    if ext & 0x01 == 0 {
+        return None;
+    }
+    //after the PES_extension_field_length
+    h.get(p + 1).map(|e| e & 0x7F)
+}
+
+pub struct Pes {
+    //90khz
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    pub stream_id_extension: Option<u8>,
+    //without the PES header
+    pub data: Vec<u8>,
+}
+
+impl Pes {
+    fn parse(mut data: Vec<u8>) -> Option<Pes> {
+        let (pts, dts) = parse_pes_timestamps(&data)?;
+        //no optional header for padding/private_stream_2 etc.
+        let header_end = if data[6] & 0xC0 == 0x80 {
+            9 + data[8] as usize
+        } else {
+            6
+        };
+        if header_end > data.len() {
+            return None;
+        }
+        let stream_id_extension = if data[3] == 0xFD {
+            stream_id_extension(&data[..header_end])
+        } else {
+            None
+        };
+        data.drain(..header_end);
+        Some(Pes {
+            pts,
+            dts,
+            stream_id_extension,
+            data,
+        })
+    }
+
+    //the AC-3 core that comes along TrueHD on the same pid
+    pub fn is_ac3_core(&self) -> bool {
+        self.stream_id_extension == Some(0x76)
+    }
+}
+
+//reassembles the PES packets of one pid
+pub struct Demuxer<R> {
+    src: R,
+    pid: u16,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    pmt_pid: Option<u16>,
+    //a PES of the pid was seen
+    found: bool,
+    //PES being collected, with its header
+    pes: Option<Vec<u8>>,
+    last_cc: Option<u8>,
+}
+
+impl<R: Read> Demuxer<R> {
+    //`src` has to be at the start of a source packet
+    pub fn new(src: R, pid: u16) -> Demuxer<R> {
+        Demuxer {
+            src,
+            pid,
+            buf: vec![0u8; SOURCE_PACKET_SIZE * 64],
+            pos: 0,
+            filled: 0,
+            pmt_pid: None,
+            found: false,
+            pes: None,
+            last_cc: None,
+        }
+    }
+
+    //false once there is no whole source packet left
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.filled - self.pos >= SOURCE_PACKET_SIZE {
+            return Ok(true);
+        }
+        self.buf.copy_within(self.pos..self.filled, 0);
+        self.filled -= self.pos;
+        self.pos = 0;
+        while self.filled < self.buf.len() {
+            let r = self.src.read(&mut self.buf[self.filled..])?;
+            if r == 0 {
+                break;
+            }
+            self.filled += r;
+        }
+        Ok(self.filled >= SOURCE_PACKET_SIZE)
+    }
+
+    //None at the end of the file, or if the PMT doesn't have the pid
+    pub fn next_pes(&mut self) -> io::Result<Option<Pes>> {
+        loop {
+            if !self.fill()? {
+                //the last one ends with the file
+                return Ok(self.pes.take().and_then(Pes::parse));
+            }
+            let sp = &self.buf[self.pos..self.pos + SOURCE_PACKET_SIZE];
+            self.pos += SOURCE_PACKET_SIZE;
+            let p = match parse_ts_packet(sp) {
+                Some(e) => e,
+                None => continue,
+            };
+
+            if p.pid == 0 && p.payload_unit_start {
+                if let Some(pmt_pid) = psi_section(p.payload).and_then(parse_pat) {
+                    self.pmt_pid = Some(pmt_pid);
+                }
+                continue;
+            }
+            if Some(p.pid) == self.pmt_pid && p.payload_unit_start {
+                let streams = psi_section(p.payload).and_then(parse_pmt);
+                if let Some(streams) = streams {
+                    //nothing to wait for
+                    if !self.found && !streams.iter().any(|e| e.0 == self.pid) {
+                        return Ok(None);
+                    }
+                }
+                continue;
+            }
+            if p.pid != self.pid {
+                continue;
+            }
+
+            //a damaged or lost packet leaves a hole in the PES being collected, drop it
+            if p.transport_error {
+                self.pes = None;
+                self.last_cc = None;
+                continue;
+            }
+            if let Some(cc) = p.continuity_counter {
+                match self.last_cc {
+                    //sent twice
+                    Some(last) if last == cc => continue,
+                    Some(last) if (last + 1) & 0x0F != cc => self.pes = None,
+                    _ => {}
+                }
+                self.last_cc = Some(cc);
+            }
+
+            if p.payload_unit_start {
+                self.found = true;
+                //unbounded (video) PES end where the next one starts
+                if let Some(done) = self.pes.replace(p.payload.to_vec()) {
+                    if let Some(e) = Pes::parse(done) {
+                        return Ok(Some(e));
+                    }
+                }
+            } else if let Some(data) = &mut self.pes {
+                data.extend_from_slice(p.payload);
+            }
+
+            //the ones with a length (audio) are complete without waiting for the next
+            if let Some(data) = &self.pes {
+                let len = if data.len() >= 6 {
+                    u16::from_be_bytes([data[4], data[5]]) as usize
+                } else {
+                    0
+                };
+                if len != 0 && data.len() >= 6 + len {
+                    let mut done = self.pes.take().unwrap();
+                    done.truncate(6 + len);
+                    if let Some(e) = Pes::parse(done) {
+                        return Ok(Some(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+//(spn, pts) of every PES with a PTS on the pid, for the whole file
+pub fn scan_pes_pts(f: &mut impl Read, pid: u16) -> io::Result<Vec<(u32, u64)>> {
+    let mut ret = Vec::new();
+    let mut buf = vec![0u8; SOURCE_PACKET_SIZE * 4096];
+    let mut spn = 0u32;
+
+    loop {
+        //fill the buffer, the last read may be short
+        let mut filled = 0;
+        while filled < buf.len() {
+            let r = f.read(&mut buf[filled..])?;
+            if r == 0 {
+                break;
+            }
+            filled += r;
+        }
+
+        for sp in buf[..filled].chunks_exact(SOURCE_PACKET_SIZE) {
+            if let Some(p) = parse_ts_packet(sp) {
+                if p.pid == pid && p.payload_unit_start && !p.transport_error {
+                    if let Some((Some(pts), _)) = parse_pes_timestamps(p.payload) {
+                        ret.push((spn, pts));
+                    }
+                }
+            }
+            spn += 1;
+        }
+
+        if filled < buf.len() {
+            break;
+        }
+    }
+    Ok(ret)
+}
+
+pub struct PacketInfo {
+    pub spn: u32,
+    pub copy_permission: u8,
+    //27mhz arrival clock, wraps after 30 bits
+    pub ats: u32,
+    pub pid: u16,
+    pub payload_unit_start: bool,
+    pub pcr: Option<u64>,
+    //90khz, only at the start of a PES
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+}
+
+//timing of `count` source packets from `start_spn`, with `pid` only the ones of it and the ones with a PCR
+pub fn walk_packets(
+    f: &mut (impl Read + Seek),
+    start_spn: u32,
+    count: u32,
+    pid: Option<u16>,
+) -> io::Result<Vec<PacketInfo>> {
+    f.seek(SeekFrom::Start(
+        start_spn as u64 * SOURCE_PACKET_SIZE as u64,
+    ))?;
+
+    let mut ret = Vec::new();
+    let mut sp = [0u8; SOURCE_PACKET_SIZE];
+    for spn in start_spn..start_spn.saturating_add(count) {
+        match f.read_exact(&mut sp) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        //lost sync
+        let p = match parse_ts_packet(&sp) {
+            Some(e) => e,
+            None => continue,
+        };
+        if pid.is_some_and(|e| e != p.pid) && p.pcr.is_none() {
+            continue;
+        }
+
+        let (copy_permission, ats) = parse_extra_header(&sp);
+        let (pts, dts) = if p.payload_unit_start {
+            parse_pes_timestamps(p.payload).unwrap_or((None, None))
+        } else {
+            (None, None)
+        };
+        ret.push(PacketInfo {
+            spn,
+            copy_permission,
+            ats,
+            pid: p.pid,
+            payload_unit_start: p.payload_unit_start,
+            pcr: p.pcr,
+            pts,
+            dts,
+        });
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PMT_PID: u16 = 0x0100;
+    const VIDEO_PID: u16 = 0x1011;
+    const AUDIO_PID: u16 = 0x1100;
+
+    //source packet with `payload`, the rest of the 184 bytes is adaptation field stuffing
+    fn packet(pid: u16, start: bool, cc: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() <= 184);
+        let mut sp = vec![0u8; 4];
+        sp.push(0x47);
+        sp.push(((start as u8) << 6) | (pid >> 8) as u8);
+        sp.push(pid as u8);
+        if payload.len() == 184 {
+            sp.push(0x10 | cc);
+        } else {
+            sp.push(0x30 | cc);
+            let stuffing = 183 - payload.len();
+            sp.push(stuffing as u8);
+            if stuffing > 0 {
+                sp.push(0x00);
+                sp.resize(sp.len() + stuffing - 1, 0xFF);
+            }
+        }
+        sp.extend_from_slice(payload);
+        assert_eq!(sp.len(), SOURCE_PACKET_SIZE);
+        sp
+    }
+
+    //split over as many packets as needed
+    fn packets(pid: u16, cc: &mut u8, data: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for (i, chunk) in data.chunks(184).enumerate() {
+            ret.extend(packet(pid, i == 0, *cc, chunk));
+            *cc = (*cc + 1) & 0x0F;
+        }
+        ret
+    }
+
+    fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+        [
+            (prefix << 4) | ((ts >> 29) as u8 & 0x0E) | 1,
+            (ts >> 22) as u8,
+            ((ts >> 14) as u8 & 0xFE) | 1,
+            (ts >> 7) as u8,
+            ((ts << 1) as u8) | 1,
+        ]
+    }
+
+    //PES with a PTS, a length of 0 if not `bounded`
+    fn pes(stream_id: u8, pts: u64, ext: Option<u8>, data: &[u8], bounded: bool) -> Vec<u8> {
+        let mut header = timestamp(0x02, pts).to_vec();
+        let mut flags = 0x80;
+        if let Some(e) = ext {
+            flags |= 0x01;
+            //PES_extension_flag_2, then its length and the stream_id_extension
+            header.extend([0x01, 0x81, e]);
+        }
+        let mut ret = vec![0, 0, 1, stream_id, 0, 0, 0x80, flags, header.len() as u8];
+        ret.extend(header);
+        ret.extend_from_slice(data);
+        if bounded {
+            let len = (ret.len() - 6) as u16;
+            ret[4..6].copy_from_slice(&len.to_be_bytes());
+        }
+        ret
+    }
+
+    fn section(table_id: u8, body: &[u8]) -> Vec<u8> {
+        //pointer field
+        let mut ret = vec![0x00, table_id];
+        let len = (5 + body.len() + 4) as u16;
+        ret.extend((0xB000 | len).to_be_bytes());
+        ret.extend([0x00, 0x01, 0xC1, 0x00, 0x00]);
+        ret.extend_from_slice(body);
+        //CRC isn't checked
+        ret.extend([0u8; 4]);
+        ret
+    }
+
+    fn pat() -> Vec<u8> {
+        let mut body = vec![0x00, 0x01];
+        body.extend((0xE000 | PMT_PID).to_be_bytes());
+        packet(0, true, 0, &section(0x00, &body))
+    }
+
+    fn pmt(streams: &[(u16, u8)]) -> Vec<u8> {
+        //PCR pid, no program descriptors
+        let mut body = vec![0xF0, 0x01, 0xF0, 0x00];
+        for (pid, stream_type) in streams {
+            body.push(*stream_type);
+            body.extend((0xE000 | pid).to_be_bytes());
+            body.extend([0xF0, 0x00]);
+        }
+        packet(PMT_PID, true, 0, &section(0x02, &body))
+    }
+
+    fn payload(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|e| (e as u8).wrapping_mul(7).wrapping_add(seed))
+            .collect()
+    }
+
+    fn demux(ts: &[u8], pid: u16) -> Vec<Pes> {
+        let mut d = Demuxer::new(ts, pid);
+        let mut ret = Vec::new();
+        while let Some(e) = d.next_pes().unwrap() {
+            ret.push(e);
+        }
+        ret
+    }
+
+    #[test]
+    fn parses_pat_and_pmt() {
+        let sp = pat();
+        let p = parse_ts_packet(&sp).unwrap();
+        assert_eq!(psi_section(p.payload).and_then(parse_pat), Some(PMT_PID));
+
+        let sp = pmt(&[(VIDEO_PID, 0x1B), (AUDIO_PID, 0x83)]);
+        let p = parse_ts_packet(&sp).unwrap();
+        assert_eq!(
+            psi_section(p.payload).and_then(parse_pmt),
+            Some(vec![(VIDEO_PID, 0x1B), (AUDIO_PID, 0x83)])
+        );
+    }
+
+    #[test]
+    fn reassembles_unbounded_pes() {
+        let first = payload(500, 1);
+        let second = payload(300, 2);
+        let mut ts = pat();
+        ts.extend(pmt(&[(VIDEO_PID, 0x1B)]));
+        let mut cc = 0;
+        ts.extend(packets(
+            VIDEO_PID,
+            &mut cc,
+            &pes(0xE0, 9000, None, &first, false),
+        ));
+        ts.extend(packets(
+            VIDEO_PID,
+            &mut cc,
+            &pes(0xE0, 12003, None, &second, false),
+        ));
+
+        let got = demux(&ts, VIDEO_PID);
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].pts, Some(9000));
+        assert_eq!(got[0].data, first);
+        //the last one ends with the file
+        assert_eq!(got[1].pts, Some(12003));
+        assert_eq!(got[1].data, second);
+    }
+
+    #[test]
+    fn bounded_pes_ends_at_its_length() {
+        let data = payload(250, 3);
+        let mut ts = Vec::new();
+        let mut cc = 0;
+        ts.extend(packets(
+            AUDIO_PID,
+            &mut cc,
+            &pes(0xBD, 1 << 32, None, &data, true),
+        ));
+        //continuation without a start, not part of the finished PES
+        ts.extend(packet(AUDIO_PID, false, cc, &[0xAB; 16]));
+
+        let mut d = Demuxer::new(&ts[..], AUDIO_PID);
+        let got = d.next_pes().unwrap().unwrap();
+        assert_eq!(got.pts, Some(1 << 32));
+        assert_eq!(got.data, data);
+        assert!(d.next_pes().unwrap().is_none());
+    }
+
+    #[test]
+    fn marks_truehd_ac3_core() {
+        let mut ts = Vec::new();
+        let mut cc = 0;
+        ts.extend(packets(
+            AUDIO_PID,
+            &mut cc,
+            &pes(0xFD, 100, Some(0x72), &payload(200, 4), true),
+        ));
+        ts.extend(packets(
+            AUDIO_PID,
+            &mut cc,
+            &pes(0xFD, 100, Some(0x76), &payload(60, 5), true),
+        ));
+        ts.extend(packets(
+            AUDIO_PID,
+            &mut cc,
+            &pes(0xFD, 175, Some(0x72), &payload(40, 6), true),
+        ));
+
+        let got = demux(&ts, AUDIO_PID);
+        let cores: Vec<bool> = got.iter().map(|e| e.is_ac3_core()).collect();
+        assert_eq!(cores, [false, true, false]);
+        assert_eq!(got[1].data, payload(60, 5));
+        assert_eq!(got[2].data, payload(40, 6));
+    }
+
+    #[test]
+    fn gives_up_when_the_pmt_lacks_the_pid() {
+        let mut ts = pat();
+        ts.extend(pmt(&[(VIDEO_PID, 0x1B)]));
+        let mut cc = 0;
+        ts.extend(packets(
+            AUDIO_PID,
+            &mut cc,
+            &pes(0xBD, 0, None, &payload(20, 7), true),
+        ));
+
+        assert!(demux(&ts, AUDIO_PID).is_empty());
+    }
+
+    #[test]
+    fn drops_pes_with_lost_packets() {
+        let first = pes(0xE0, 9000, None, &payload(500, 8), false);
+        let second = payload(100, 9);
+        let mut cc = 0;
+        let mut ts = packets(VIDEO_PID, &mut cc, &first);
+        //lose the middle packet
+        ts.drain(SOURCE_PACKET_SIZE..SOURCE_PACKET_SIZE * 2);
+        ts.extend(packets(
+            VIDEO_PID,
+            &mut cc,
+            &pes(0xE0, 12003, None, &second, false),
+        ));
+
+        let got = demux(&ts, VIDEO_PID);
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].data, second);
+    }
+
+    #[test]
+    fn drops_pes_with_transport_errors() {
+        let second = payload(100, 10);
+        let mut cc = 0;
+        let mut ts = packets(
+            VIDEO_PID,
+            &mut cc,
+            &pes(0xE0, 9000, None, &payload(300, 11), false),
+        );
+        //transport_error_indicator on the continuation
+        ts[SOURCE_PACKET_SIZE + 5] |= 0x80;
+        ts.extend(packets(
+            VIDEO_PID,
+            &mut cc,
+            &pes(0xE0, 12003, None, &second, false),
+        ));
+
+        let got = demux(&ts, VIDEO_PID);
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].data, second);
+    }
+
+    #[test]
+    fn skips_duplicate_packets() {
+        let data = payload(300, 12);
+        let mut cc = 0;
+        let mut ts = packets(VIDEO_PID, &mut cc, &pes(0xE0, 9000, None, &data, false));
+        let dup = ts[SOURCE_PACKET_SIZE..].to_vec();
+        ts.extend(dup);
+
+        let got = demux(&ts, VIDEO_PID);
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].data, data);
+    }
+}
@@ -0,0 +1,165 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{ReadBytesExt, BE};
+
+use crate::error::{Error, Result};
+
+pub struct PlayItem {
+    pub clip_name: String,
+    pub stc_id: u8,
+    //45khz
+    pub in_time: u32,
+    pub out_time: u32,
+}
+
+pub struct MPLSResult {
+    pub play_items: Vec<PlayItem>,
+}
+
+fn read_string(f: &mut impl Read, len: usize) -> Result<String> {
+    let mut buf = vec![0u8; len];
+    f.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn parse_play_item(f: &mut (impl Read + Seek)) -> Result<PlayItem> {
+    let length = f.read_u16::<BE>()?;
+    let start = f.stream_position()?;
+
+    let clip_name = read_string(f, 5)?;
+    let _codec_id = read_string(f, 4)?;
+
+    let mut blob = [0u8; 2];
+    f.read_exact(&mut blob)?;
+    let mut bread = bitreader::BitReader::new(&blob);
+    bread.skip(11)?; //reserved
+    let _is_multi_angle = bread.read_bool()?;
+    let _connection_condition = bread.read_u8(4)?;
+
+    let stc_id = f.read_u8()?;
+    let in_time = f.read_u32::<BE>()?;
+    let out_time = f.read_u32::<BE>()?;
+
+    //UO_mask, random access, still mode, angles and STN table are not needed
+    f.seek(SeekFrom::Start(start + length as u64))?;
+
+    Ok(PlayItem {
+        clip_name,
+        stc_id,
+        in_time,
+        out_time,
+    })
+}
+
+pub fn read_mpls(mpls_file: &mut (impl Read + Seek)) -> Result<MPLSResult> {
+    mpls_file.seek(SeekFrom::Start(0))?;
+    let type_indicator = read_string(mpls_file, 4)?;
+    if type_indicator != "MPLS" {
+        return Err(Error::InvalidMpls(format!(
+            "Not a MPLS file: {}",
+            type_indicator
+        )));
+    }
+    let _version = read_string(mpls_file, 4)?;
+
+    let playlist_addr = mpls_file.read_u32::<BE>()?;
+    let _playlist_mark_addr = mpls_file.read_u32::<BE>()?;
+    let _ext_data_addr = mpls_file.read_u32::<BE>()?;
+
+    mpls_file.seek(SeekFrom::Start(playlist_addr as u64))?;
+    let _playlist_length = mpls_file.read_u32::<BE>()?;
+    let _reserved = mpls_file.read_u16::<BE>()?;
+    let num_play_items = mpls_file.read_u16::<BE>()?;
+    let _num_sub_paths = mpls_file.read_u16::<BE>()?;
+
+    let mut play_items = Vec::with_capacity(num_play_items as usize);
+    for _ in 0..num_play_items {
+        play_items.push(parse_play_item(mpls_file)?);
+    }
+
+    Ok(MPLSResult { play_items })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    //`extra` stands in for the UO mask, STN table... that get skipped
+    fn play_item(clip: &str, stc_id: u8, in_time: u32, out_time: u32, extra: usize) -> Vec<u8> {
+        let mut d = Vec::new();
+        d.extend(clip.as_bytes());
+        d.extend(b"M2TS");
+        //multi angle off, connection condition 1
+        d.extend([0x00, 0x01]);
+        d.push(stc_id);
+        d.extend(in_time.to_be_bytes());
+        d.extend(out_time.to_be_bytes());
+        d.resize(d.len() + extra, 0xFF);
+        let mut ret = (d.len() as u16).to_be_bytes().to_vec();
+        ret.extend(d);
+        ret
+    }
+
+    fn mpls(items: &[Vec<u8>]) -> Vec<u8> {
+        let playlist_addr = 40u32;
+        let mut d = b"MPLS0200".to_vec();
+        d.extend(playlist_addr.to_be_bytes());
+        d.extend([0u8; 8]);
+        d.resize(playlist_addr as usize, 0);
+
+        let body = items.concat();
+        d.extend((6 + body.len() as u32).to_be_bytes());
+        d.extend([0, 0]);
+        d.extend((items.len() as u16).to_be_bytes());
+        d.extend([0, 0]);
+        d.extend(body);
+        d
+    }
+
+    #[test]
+    fn reads_play_items() {
+        let data = mpls(&[
+            play_item("00001", 0, 90_000, 180_000, 0),
+            play_item("00002", 1, 45_000, 4_545_000, 30),
+        ]);
+        let res = read_mpls(&mut Cursor::new(data)).unwrap();
+        assert_eq!(res.play_items.len(), 2);
+
+        let first = &res.play_items[0];
+        assert_eq!(first.clip_name, "00001");
+        assert_eq!(
+            (first.stc_id, first.in_time, first.out_time),
+            (0, 90_000, 180_000)
+        );
+        //found after skipping the rest of the first one
+        let second = &res.play_items[1];
+        assert_eq!(second.clip_name, "00002");
+        assert_eq!(
+            (second.stc_id, second.in_time, second.out_time),
+            (1, 45_000, 4_545_000)
+        );
+    }
+
+    #[test]
+    fn skips_unused_play_item_data() {
+        let data = mpls(&[
+            play_item("00003", 0, 0, 1, 100),
+            play_item("00004", 0, 2, 3, 0),
+        ]);
+        let res = read_mpls(&mut Cursor::new(data)).unwrap();
+        assert_eq!(res.play_items[1].clip_name, "00004");
+        assert_eq!(res.play_items[1].in_time, 2);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut data = mpls(&[play_item("00001", 0, 0, 1, 0)]);
+        data[..4].copy_from_slice(b"HDMV");
+        assert!(matches!(
+            read_mpls(&mut Cursor::new(data)),
+            Err(Error::InvalidMpls(_))
+        ));
+    }
+}
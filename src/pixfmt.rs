@@ -0,0 +1,84 @@
+use ffmpeg_sys::AVPixelFormat::{self, *};
+use ffmpeg_sys::{AVChromaLocation, AVColorRange, AVFrame};
+use vapoursynth::format::PresetFormat;
+
+//decoder output formats that can be served and what they become in vapoursynth,
+//the yuvj (full range) variants have the same layout as their yuv counterparts
+const FORMATS: &[(AVPixelFormat, PresetFormat)] = &[
+    (AV_PIX_FMT_YUV420P, PresetFormat::YUV420P8),
+    (AV_PIX_FMT_YUVJ420P, PresetFormat::YUV420P8),
+    (AV_PIX_FMT_YUV422P, PresetFormat::YUV422P8),
+    (AV_PIX_FMT_YUVJ422P, PresetFormat::YUV422P8),
+    (AV_PIX_FMT_YUV444P, PresetFormat::YUV444P8),
+    (AV_PIX_FMT_YUVJ444P, PresetFormat::YUV444P8),
+    (AV_PIX_FMT_YUV420P10LE, PresetFormat::YUV420P10),
+    (AV_PIX_FMT_YUV422P10LE, PresetFormat::YUV422P10),
+    (AV_PIX_FMT_YUV444P10LE, PresetFormat::YUV444P10),
+    (AV_PIX_FMT_YUV420P16LE, PresetFormat::YUV420P16),
+    (AV_PIX_FMT_YUV422P16LE, PresetFormat::YUV422P16),
+    (AV_PIX_FMT_YUV444P16LE, PresetFormat::YUV444P16),
+    (AV_PIX_FMT_GRAY8, PresetFormat::Gray8),
+    (AV_PIX_FMT_GRAY16LE, PresetFormat::Gray16),
+];
+
+//AVFrame.format is a plain int
+pub fn from_raw(raw: i32) -> Option<AVPixelFormat> {
+    FORMATS.iter().find(|e| e.0 as i32 == raw).map(|e| e.0)
+}
+
+pub fn vs_format(fmt: AVPixelFormat) -> Option<PresetFormat> {
+    FORMATS.iter().find(|e| e.0 == fmt).map(|e| e.1)
+}
+
+//the yuvj formats are full range even without color_range set
+fn is_full_range_format(raw: i32) -> bool {
+    [
+        AV_PIX_FMT_YUVJ420P,
+        AV_PIX_FMT_YUVJ422P,
+        AV_PIX_FMT_YUVJ444P,
+    ]
+    .iter()
+    .any(|e| *e as i32 == raw)
+}
+
+//H.273 codes vapoursynth has constants for. Unspecified, reserved and the ones it lacks
+//(ST 2085 matrix, BT.1361 and ST 428 transfer) aren't set
+const VS_MATRIX: &[i64] = &[0, 1, 4, 5, 6, 7, 8, 9, 10, 12, 13, 14];
+const VS_TRANSFER: &[i64] = &[1, 4, 5, 6, 7, 8, 9, 10, 11, 13, 14, 15, 16, 18];
+const VS_PRIMARIES: &[i64] = &[1, 4, 5, 6, 7, 8, 9, 10, 11, 12, 22];
+
+//vapoursynth color props from the VUI/sequence header, unspecified ones are left out.
+//matrix, transfer and primaries use the H.273 numbering in both
+pub fn color_props(f: &AVFrame) -> Vec<(&'static str, i64)> {
+    let mut props = Vec::new();
+    let coded = [
+        ("_Matrix", f.colorspace as i64, VS_MATRIX),
+        ("_Transfer", f.color_trc as i64, VS_TRANSFER),
+        ("_Primaries", f.color_primaries as i64, VS_PRIMARIES),
+    ];
+    for (key, value, defined) in coded {
+        if defined.contains(&value) {
+            props.push((key, value));
+        }
+    }
+
+    //0 full, 1 limited in vapoursynth
+    match f.color_range {
+        AVColorRange::AVCOL_RANGE_JPEG => props.push(("_ColorRange", 0)),
+        AVColorRange::AVCOL_RANGE_MPEG => props.push(("_ColorRange", 1)),
+        _ if is_full_range_format(f.format) => props.push(("_ColorRange", 0)),
+        _ => {}
+    }
+
+    //vapoursynth starts at left instead of unspecified
+    if f.chroma_location != AVChromaLocation::AVCHROMA_LOC_UNSPECIFIED {
+        props.push(("_ChromaLocation", f.chroma_location as i64 - 1));
+    }
+
+    let sar = f.sample_aspect_ratio;
+    if sar.num > 0 && sar.den > 0 {
+        props.push(("_SARNum", sar.num as i64));
+        props.push(("_SARDen", sar.den as i64));
+    }
+    props
+}
@@ -1,4 +1,9 @@
+use std::io::{Read, Write};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
 use super::clpi::*;
+use crate::error::{Error, Result};
 
 #[derive(Clone)]
 pub struct PredictedKeyFrame {
@@ -7,30 +12,358 @@ pub struct PredictedKeyFrame {
     pub spn: u32,
 }
 
-pub fn predict_frame_numbers(a: &[Combined]) -> Vec<PredictedKeyFrame> {
-    let frate = 24000.0 / 1001.0;
+//used when the clpi has no (known) frame rate code
+pub const FALLBACK_FRATE: (u32, u32) = (24000, 1001);
+
+//frames (field pairs) per second, `interlaced` comes from the decoded frames.
+//Some authoring tools write the field rate for 480i/576i/1080i
+pub fn clip_frame_rate(clpi: &CLPIResult, pid: u16, interlaced: bool) -> (u32, u32) {
+    let rate = clpi.frame_rate(pid).unwrap_or(FALLBACK_FRATE);
+    if interlaced && matches!(rate, (50, 1) | (60000, 1001)) {
+        return (rate.0 / 2, rate.1);
+    }
+    rate
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+//rate of the coded frames when `frames` are displayed for `half_frames` of `frame_rate`,
+//snapped to a regular cadence (3:2 pulldown is 2.5 half frames per frame)
+pub fn coded_frame_rate(frame_rate: (u32, u32), frames: u64, half_frames: u64) -> (u32, u32) {
+    if frames == 0 || half_frames <= frames * 2 {
+        return frame_rate;
+    }
+    //in quarter frames per frame
+    let per_frame = ((half_frames * 2) as f64 / frames as f64).round() as u64;
+    let n = frame_rate.0 as u64 * 4;
+    let d = frame_rate.1 as u64 * per_frame;
+    let g = gcd(n, d);
+    ((n / g) as u32, (d / g) as u32)
+}
+
+//one STC sequence of the clip, frame numbers continue across them
+struct StcSegment {
+    id: u8,
+    spn_start: u32,
+    pts_start: u32,
+    first_frame: u64,
+}
+
+//45khz ticks to frames, negative (or wrapped) differences count as 0
+fn frames_between(from: u32, to: u32, frate: f64) -> u64 {
+    let diff = to.wrapping_sub(from);
+    if diff > i32::MAX as u32 {
+        return 0;
+    }
+    ((diff as f64 / 45_000.0) * frate).round() as _
+}
+
+fn stc_segments(a: &[Combined], stcs: &[&StcSequence], frate: f64) -> Vec<StcSegment> {
+    if stcs.is_empty() {
+        return vec![StcSegment {
+            id: 0,
+            spn_start: 0,
+            pts_start: a[0].pts as u32,
+            first_frame: 0,
+        }];
+    }
+
+    let mut first_frame = 0;
+    stcs.iter()
+        .map(|e| {
+            let seg = StcSegment {
+                id: e.id,
+                spn_start: e.spn_stc_start,
+                pts_start: e.presentation_start_time,
+                first_frame,
+            };
+            first_frame +=
+                frames_between(e.presentation_start_time, e.presentation_end_time, frate);
+            seg
+        })
+        .collect()
+}
+
+pub fn predict_frame_numbers(
+    a: &[Combined],
+    stcs: &[&StcSequence],
+    frame_rate: (u32, u32),
+) -> Vec<PredictedKeyFrame> {
+    let frate = frame_rate.0 as f64 / frame_rate.1 as f64;
 
-    let frame_0 = a[0].pts;
+    let segments = stc_segments(a, stcs, frate);
 
     a.iter()
-        .map(|e| PredictedKeyFrame {
-            number: (((e.pts - frame_0) as f64 / 45_000.0) * frate).round() as _,
-            pts: e.pts,
-            spn: e.spn,
+        .map(|e| {
+            let seg = segments
+                .iter()
+                .rev()
+                .find(|s| s.spn_start <= e.spn)
+                .unwrap_or(&segments[0]);
+
+            PredictedKeyFrame {
+                number: seg.first_frame + frames_between(seg.pts_start, e.pts as u32, frate),
+                pts: e.pts,
+                spn: e.spn,
+            }
         })
         .collect()
 }
 
-pub fn get_frame_dump_info(num: u64, prd: &[PredictedKeyFrame]) -> (usize, u64) {
-    let mut closest = 0;
-    for (i, a) in prd.iter().enumerate() {
-        if a.number > num {
-            closest = i - 1;
-            break;
+//frame number a 45khz presentation time (e.g. a PlayItem IN_time) of a STC sequence falls on
+pub fn pts_to_frame(
+    pts: u32,
+    stc_id: u8,
+    a: &[Combined],
+    stcs: &[&StcSequence],
+    frame_rate: (u32, u32),
+) -> u64 {
+    let frate = frame_rate.0 as f64 / frame_rate.1 as f64;
+
+    let segments = stc_segments(a, stcs, frate);
+    let seg = segments
+        .iter()
+        .find(|s| s.id == stc_id)
+        .unwrap_or(&segments[0]);
+
+    seg.first_frame + frames_between(seg.pts_start, pts, frate)
+}
+
+//inverse of pts_to_frame, the 45khz presentation time of a clip frame
+pub fn frame_to_pts(
+    frame: u64,
+    a: &[Combined],
+    stcs: &[&StcSequence],
+    frame_rate: (u32, u32),
+) -> u32 {
+    let frate = frame_rate.0 as f64 / frame_rate.1 as f64;
+
+    let segments = stc_segments(a, stcs, frate);
+    let seg = segments
+        .iter()
+        .rev()
+        .find(|s| s.first_frame <= frame)
+        .unwrap_or(&segments[0]);
+
+    let ticks = ((frame - seg.first_frame) as f64 / frate * 45_000.0).round() as u32;
+    seg.pts_start.wrapping_add(ticks)
+}
+
+//signed 90khz difference with 33 bit wraparound
+fn ticks_since(pts: u64, start: u64) -> i64 {
+    let d = (pts as i64 - start as i64).rem_euclid(1 << 33);
+    if d >= 1 << 32 {
+        d - (1 << 33)
+    } else {
+        d
+    }
+}
+
+struct ExactStc {
+    id: u8,
+    spn_start: u32,
+    //90khz
+    pts_start: u64,
+    first_frame: u64,
+    //sorted, relative to pts_start
+    ticks: Vec<i64>,
+}
+
+//frame numbers from the PTS of every coded picture instead of estimated from the EP map
+pub struct ExactIndex {
+    stcs: Vec<ExactStc>,
+}
+
+//90khz
+fn frame_ticks(frame_rate: (u32, u32)) -> f64 {
+    90_000.0 * frame_rate.1 as f64 / frame_rate.0 as f64
+}
+
+impl ExactIndex {
+    pub fn new(pes: &[(u32, u64)], stcs: &[&StcSequence], frame_rate: (u32, u32)) -> ExactIndex {
+        let mut ex: Vec<ExactStc> = if stcs.is_empty() {
+            vec![ExactStc {
+                id: 0,
+                spn_start: 0,
+                pts_start: pes.first().map(|e| e.1).unwrap_or(0),
+                first_frame: 0,
+                ticks: Vec::new(),
+            }]
+        } else {
+            stcs.iter()
+                .map(|e| ExactStc {
+                    id: e.id,
+                    spn_start: e.spn_stc_start,
+                    pts_start: e.presentation_start_time as u64 * 2,
+                    first_frame: 0,
+                    ticks: Vec::new(),
+                })
+                .collect()
+        };
+
+        for (spn, pts) in pes {
+            let stc = ex.iter_mut().rev().find(|s| s.spn_start <= *spn);
+            if let Some(stc) = stc {
+                stc.ticks.push(ticks_since(*pts, stc.pts_start));
+            }
+        }
+
+        //PAFF fields can be separate PES packets half a frame apart, they are one coded frame
+        let min_gap = (frame_ticks(frame_rate) * 0.75) as i64;
+        let mut first_frame = 0;
+        for stc in ex.iter_mut() {
+            //display order
+            stc.ticks.sort_unstable();
+            let mut last = None;
+            stc.ticks.retain(|e| {
+                let keep = match last {
+                    Some(l) => e - l >= min_gap,
+                    None => true,
+                };
+                if keep {
+                    last = Some(*e);
+                }
+                keep
+            });
+            stc.first_frame = first_frame;
+            first_frame += stc.ticks.len() as u64;
+        }
+        ExactIndex { stcs: ex }
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u32::<LE>(self.stcs.len() as u32)?;
+        for stc in &self.stcs {
+            w.write_u8(stc.id)?;
+            w.write_u32::<LE>(stc.spn_start)?;
+            w.write_u64::<LE>(stc.pts_start)?;
+            w.write_u64::<LE>(stc.first_frame)?;
+            w.write_u64::<LE>(stc.ticks.len() as u64)?;
+            for t in &stc.ticks {
+                w.write_i64::<LE>(*t)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<ExactIndex> {
+        let num_stcs = r.read_u32::<LE>()?;
+        let mut stcs = Vec::new();
+        for _ in 0..num_stcs {
+            let id = r.read_u8()?;
+            let spn_start = r.read_u32::<LE>()?;
+            let pts_start = r.read_u64::<LE>()?;
+            let first_frame = r.read_u64::<LE>()?;
+            let num_ticks = r.read_u64::<LE>()?;
+            let mut ticks = Vec::new();
+            for _ in 0..num_ticks {
+                ticks.push(r.read_i64::<LE>()?);
+            }
+            stcs.push(ExactStc {
+                id,
+                spn_start,
+                pts_start,
+                first_frame,
+                ticks,
+            });
+        }
+        if stcs.is_empty() {
+            return Err(Error::InvalidIndex("no stc sequences".to_owned()));
         }
+        Ok(ExactIndex { stcs })
     }
+
+    fn frame_at(&self, stc: &ExactStc, pts: u64) -> u64 {
+        let t = ticks_since(pts, stc.pts_start);
+        stc.first_frame + stc.ticks.partition_point(|e| *e < t) as u64
+    }
+
+    //number of the keyframe at spn with the 45khz EP map pts
+    pub fn keyframe_number(&self, spn: u32, pts: u64) -> u64 {
+        let stc = self
+            .stcs
+            .iter()
+            .rev()
+            .find(|s| s.spn_start <= spn)
+            .unwrap_or(&self.stcs[0]);
+        //the ep map lost the lowest bit
+        self.frame_at(stc, pts * 2)
+    }
+
+    pub fn pts_to_frame(&self, pts: u32, stc_id: u8) -> u64 {
+        let stc = self
+            .stcs
+            .iter()
+            .find(|s| s.id == stc_id)
+            .unwrap_or(&self.stcs[0]);
+        self.frame_at(stc, pts as u64 * 2)
+    }
+
+    //fields each frame is displayed for (2 + repeated fields), from the time to the next frame.
+    //`frame_rate` is the field pair rate, the last frame of a STC sequence gets 2
+    pub fn field_counts(&self, first: u64, count: u64, frame_rate: (u32, u32)) -> Vec<u8> {
+        let field = frame_ticks(frame_rate) / 2.0;
+        (first..first + count)
+            .map(|frame| {
+                let stc = self
+                    .stcs
+                    .iter()
+                    .rev()
+                    .find(|s| s.first_frame <= frame)
+                    .unwrap_or(&self.stcs[0]);
+                let idx = (frame - stc.first_frame) as usize;
+                match (stc.ticks.get(idx), stc.ticks.get(idx + 1)) {
+                    (Some(a), Some(b)) => ((b - a) as f64 / field).round().clamp(2.0, 6.0) as u8,
+                    _ => 2,
+                }
+            })
+            .collect()
+    }
+
+    //coded frame rate from the display time of every frame instead of only the last GOP
+    pub fn coded_frame_rate(&self, frame_rate: (u32, u32)) -> (u32, u32) {
+        let field = frame_ticks(frame_rate) / 2.0;
+        let mut frames = 0;
+        let mut half_frames = 0;
+        for stc in &self.stcs {
+            for w in stc.ticks.windows(2) {
+                frames += 1;
+                half_frames += ((w[1] - w[0]) as f64 / field).round() as u64;
+            }
+        }
+        coded_frame_rate(frame_rate, frames, half_frames)
+    }
+
+    //45khz
+    pub fn frame_to_pts(&self, frame: u64) -> u32 {
+        let stc = self
+            .stcs
+            .iter()
+            .rev()
+            .find(|s| s.first_frame <= frame && !s.ticks.is_empty())
+            .unwrap_or(&self.stcs[0]);
+        let idx = ((frame - stc.first_frame) as usize).min(stc.ticks.len().saturating_sub(1));
+        let ticks = stc.ticks.get(idx).copied().unwrap_or(0);
+        ((stc.pts_start as i64 + ticks).rem_euclid(1 << 33) / 2) as u32
+    }
+}
+
+//gop `num` is in and its offset in the gop
+pub fn get_frame_dump_info(num: u64, prd: &[PredictedKeyFrame]) -> Result<(usize, u64)> {
+    //frames before the first keyframe can't be decoded
+    let next = prd.iter().position(|e| e.number > num).unwrap_or(prd.len());
+    if next == 0 {
+        return Err(Error::NoKeyframe(num));
+    }
+    let closest = next - 1;
     let frames_offset = num - prd[closest].number;
-    (closest, frames_offset)
+    Ok((closest, frames_offset))
 }
 /*
 pub fn dump_frame(
@@ -59,3 +392,210 @@ pub fn dump_frame(
     .unwrap();
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stc(id: u8, spn_stc_start: u32, start: u32, end: u32) -> StcSequence {
+        StcSequence {
+            id,
+            spn_stc_start,
+            presentation_start_time: start,
+            presentation_end_time: end,
+        }
+    }
+
+    fn entry(pts: u64, spn: u32) -> Combined {
+        Combined { pts, spn }
+    }
+
+    //two STC sequences of 10 seconds at 24 fps
+    fn two_stcs() -> Vec<StcSequence> {
+        vec![
+            stc(0, 0, 45_000, 495_000),
+            stc(1, 1_000, 900_000, 1_350_000),
+        ]
+    }
+
+    #[test]
+    fn predicts_frames_per_stc_sequence() {
+        let stcs = two_stcs();
+        let stcs: Vec<_> = stcs.iter().collect();
+        let a = [
+            entry(45_000, 0),
+            entry(90_000, 500),
+            entry(900_000, 1_000),
+            entry(922_500, 1_200),
+        ];
+        let numbers: Vec<_> = predict_frame_numbers(&a, &stcs, (24, 1))
+            .iter()
+            .map(|e| e.number)
+            .collect();
+        //the second sequence continues after the 240 frames of the first
+        assert_eq!(numbers, [0, 24, 240, 252]);
+    }
+
+    #[test]
+    fn predicts_from_the_first_entry_without_stc_sequences() {
+        let a = [entry(1_000, 0), entry(46_000, 100), entry(91_000, 200)];
+        let numbers: Vec<_> = predict_frame_numbers(&a, &[], (24000, 1001))
+            .iter()
+            .map(|e| e.number)
+            .collect();
+        assert_eq!(numbers, [0, 24, 48]);
+    }
+
+    #[test]
+    fn converts_between_pts_and_frames() {
+        let stcs = two_stcs();
+        let stcs: Vec<_> = stcs.iter().collect();
+        let a = [entry(45_000, 0), entry(900_000, 1_000)];
+
+        assert_eq!(pts_to_frame(67_500, 0, &a, &stcs, (24, 1)), 12);
+        assert_eq!(pts_to_frame(945_000, 1, &a, &stcs, (24, 1)), 264);
+        //before the start of the sequence
+        assert_eq!(pts_to_frame(0, 0, &a, &stcs, (24, 1)), 0);
+
+        assert_eq!(frame_to_pts(12, &a, &stcs, (24, 1)), 67_500);
+        assert_eq!(frame_to_pts(264, &a, &stcs, (24, 1)), 945_000);
+    }
+
+    //25 fps, a frame is 3600 ticks at 90khz
+    fn exact_index() -> ExactIndex {
+        let stcs = two_stcs();
+        let stcs: Vec<_> = stcs.iter().collect();
+        let pes = [
+            //decode order, the second field of the first frame is its own PES
+            (0, 90_000),
+            (0, 91_800),
+            (10, 100_800),
+            (20, 93_600),
+            (30, 97_200),
+            (1_000, 1_800_000),
+            (1_100, 1_803_600),
+        ];
+        ExactIndex::new(&pes, &stcs, (25, 1))
+    }
+
+    #[test]
+    fn exact_keyframe_numbers() {
+        let index = exact_index();
+        assert_eq!(index.keyframe_number(0, 45_000), 0);
+        assert_eq!(index.keyframe_number(10, 50_400), 3);
+        //the second sequence starts after the 4 frames of the first
+        assert_eq!(index.keyframe_number(1_000, 900_000), 4);
+        assert_eq!(index.pts_to_frame(901_800, 1), 5);
+
+        assert_eq!(index.frame_to_pts(1), 46_800);
+        assert_eq!(index.frame_to_pts(5), 901_800);
+    }
+
+    #[test]
+    fn exact_index_round_trip() {
+        let index = exact_index();
+        let mut buf = Vec::new();
+        index.write(&mut buf).unwrap();
+        let read = ExactIndex::read(&mut buf.as_slice()).unwrap();
+        for (spn, pts) in [
+            (0, 45_000),
+            (10, 50_400),
+            (1_000, 900_000),
+            (1_100, 901_800),
+        ] {
+            assert_eq!(
+                read.keyframe_number(spn, pts),
+                index.keyframe_number(spn, pts)
+            );
+        }
+
+        assert!(matches!(
+            ExactIndex::read(&mut &0u32.to_le_bytes()[..]),
+            Err(Error::InvalidIndex(_))
+        ));
+    }
+
+    fn keyframes(numbers: &[u64]) -> Vec<PredictedKeyFrame> {
+        numbers
+            .iter()
+            .map(|&number| PredictedKeyFrame {
+                number,
+                pts: 0,
+                spn: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_gop_of_a_frame() {
+        let prd = keyframes(&[0, 24, 48]);
+        assert_eq!(get_frame_dump_info(0, &prd).unwrap(), (0, 0));
+        assert_eq!(get_frame_dump_info(23, &prd).unwrap(), (0, 23));
+        assert_eq!(get_frame_dump_info(24, &prd).unwrap(), (1, 0));
+        //the last gop has no known end
+        assert_eq!(get_frame_dump_info(100, &prd).unwrap(), (2, 52));
+    }
+
+    #[test]
+    fn frames_before_the_first_keyframe() {
+        let prd = keyframes(&[2, 26]);
+        assert!(matches!(
+            get_frame_dump_info(1, &prd),
+            Err(Error::NoKeyframe(1))
+        ));
+        assert!(matches!(
+            get_frame_dump_info(0, &[]),
+            Err(Error::NoKeyframe(0))
+        ));
+    }
+
+    //23.976 fps film with 3:2 pulldown, coded at 29.97
+    fn pulldown_index() -> ExactIndex {
+        let pes: Vec<_> = [0, 4_505, 7_508, 12_012, 15_015]
+            .iter()
+            .map(|t| (0, 90_000 + t))
+            .collect();
+        ExactIndex::new(&pes, &[], (30000, 1001))
+    }
+
+    #[test]
+    fn counts_repeated_fields() {
+        let index = pulldown_index();
+        //the last frame has nothing after it and gets 2
+        assert_eq!(index.field_counts(0, 5, (30000, 1001)), [3, 2, 3, 2, 2]);
+        assert_eq!(index.field_counts(1, 2, (30000, 1001)), [2, 3]);
+        assert_eq!(index.coded_frame_rate((30000, 1001)), (24000, 1001));
+    }
+
+    #[test]
+    fn coded_frame_rate_of_pulldown() {
+        assert_eq!(coded_frame_rate((30000, 1001), 4, 10), (24000, 1001));
+        assert_eq!(coded_frame_rate((30000, 1001), 4, 8), (30000, 1001));
+        assert_eq!(coded_frame_rate((25, 1), 0, 0), (25, 1));
+    }
+
+    #[test]
+    fn halves_field_rates_of_interlaced_clips() {
+        let streams = [(0x1011, 6), (0x1012, 1)]
+            .iter()
+            .map(|&(pid, frame_rate)| ProgramStream {
+                pid,
+                coding_type: 0x1B,
+                video_format: 4,
+                frame_rate,
+            })
+            .collect();
+        let clpi = CLPIResult {
+            clip_info: ClipInfo::default(),
+            clip_marks: Vec::new(),
+            extension_data: Vec::new(),
+            ep_streams: Vec::new(),
+            atc_sequences: Vec::new(),
+            programs: vec![Program { streams }],
+        };
+        assert_eq!(clip_frame_rate(&clpi, 0x1011, true), (25, 1));
+        assert_eq!(clip_frame_rate(&clpi, 0x1011, false), (50, 1));
+        assert_eq!(clip_frame_rate(&clpi, 0x1012, true), (24000, 1001));
+        assert_eq!(clip_frame_rate(&clpi, 0x1013, false), FALLBACK_FRATE);
+    }
+}
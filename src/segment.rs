@@ -0,0 +1,444 @@
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Error};
+
+use crate::stream_source::{self, FileSource, SourceReader, StreamSource};
+use crate::{clpi, fcache, ffmpeg_stuff, index_cache, m2ts, mpls, pixfmt, predict, udf};
+
+#[derive(Default)]
+pub struct OpenOptions {
+    //video pid, None for the first one with an EP map
+    pub pid: Option<u16>,
+    //scan the whole m2ts for exact keyframe numbers
+    pub exact: bool,
+    //keep the index on disk
+    pub cache: bool,
+    //memory budget for the read gops of each clip, 0 for unbounded
+    pub cache_bytes: u64,
+    //map the files instead of reading them into the gop cache
+    pub mmap: bool,
+}
+
+//one clip (or the IN/OUT trimmed part of one) in the output timeline
+pub struct Segment {
+    pub clpi: clpi::CLPIResult,
+    //video stream that is decoded and whose EP map is used
+    pub pid: u16,
+    pub ep_idx: usize,
+    pub codec_id: ffmpeg_stuff::ffmpeg_sys::AVCodecID,
+    pub pred: Vec<predict::PredictedKeyFrame>,
+    pub exact: Option<predict::ExactIndex>,
+    pub backend: Arc<dyn StreamSource>,
+
+    //frames shown before the first keyframe (leading pictures of an open gop), they can't be
+    //decoded and are left out. Clip frames are numbered from the first keyframe on
+    pub leading_frames: u64,
+    //total decodable frames in the clip
+    pub clip_frames: u64,
+    //first clip frame used
+    pub in_frame: u64,
+    //frames used
+    pub num_frames: u64,
+    //output frame number of in_frame
+    pub start: u64,
+
+    pub frame_rate_n: u32,
+    pub frame_rate_d: u32,
+    pub analisys: ffmpeg_stuff::Analisys,
+    pub vs_format: vapoursynth::format::PresetFormat,
+}
+
+//keyframe numbers plus what only decoding the end of the clip tells
+fn build_index(
+    stream: &Arc<dyn StreamSource>,
+    clpi: &clpi::CLPIResult,
+    ep: &clpi::EpMapStream,
+    pid: u16,
+    codec_id: ffmpeg_stuff::ffmpeg_sys::AVCodecID,
+    exact: bool,
+) -> Result<index_cache::CachedIndex, Error> {
+    //only the spn of the keyframes is used until the frame rate is known
+    let gops = predict::predict_frame_numbers(
+        &ep.combined,
+        &clpi.stc_sequences(),
+        predict::FALLBACK_FRATE,
+    );
+    if gops.is_empty() {
+        bail!("EP map of stream 0x{:04X} has no entries", pid);
+    }
+
+    let inner = Arc::new(fcache::FileCacheBackend::new(stream.clone(), &gops, 0));
+
+    let caached = fcache::FCached::new(inner);
+
+    //Get format and end frames
+    let analisys = unsafe {
+        let mut setup =
+            ffmpeg_stuff::setup_decoder(caached, gops[gops.len() - 1].spn, pid, codec_id)
+                .context("Failed to open the last GOP")?;
+        ffmpeg_stuff::analyse_end(&mut setup).context("Failed to decode the last GOP")?
+    };
+
+    let frame_rate = predict::clip_frame_rate(clpi, pid, analisys.interlaced);
+    let mut pred = predict::predict_frame_numbers(&ep.combined, &clpi.stc_sequences(), frame_rate);
+
+    //with pulldown the clpi has the display rate and frames are numbered as coded. The cadence
+    //isn't guessed from the last GOP, repeated fields in it make the scan run even without `exact`
+    let pulldown = analisys.last_packet_half_frames > analisys.last_packet_frame_cnt * 2;
+    let exact = if exact || pulldown {
+        let pes = m2ts::scan_pes_pts(&mut BufReader::new(SourceReader::new(stream.clone())), pid)?;
+        let index = predict::ExactIndex::new(&pes, &clpi.stc_sequences(), frame_rate);
+        for kf in pred.iter_mut() {
+            kf.number = index.keyframe_number(kf.spn, kf.pts);
+        }
+        Some(index)
+    } else {
+        None
+    };
+
+    let coded_rate = match &exact {
+        Some(index) => index.coded_frame_rate(frame_rate),
+        None => frame_rate,
+    };
+
+    Ok(index_cache::CachedIndex {
+        pred,
+        exact,
+        width: analisys.width,
+        height: analisys.height,
+        raw_format: analisys.raw_format,
+        last_packet_frame_cnt: analisys.last_packet_frame_cnt,
+        last_packet_half_frames: analisys.last_packet_half_frames,
+        interlaced: analisys.interlaced,
+        frame_rate_n: coded_rate.0,
+        frame_rate_d: coded_rate.1,
+    })
+}
+
+impl Segment {
+    pub fn open(
+        stream: Arc<dyn StreamSource>,
+        clip_info: Arc<dyn StreamSource>,
+        opts: &OpenOptions,
+    ) -> Result<Segment, Error> {
+        let clpi = match clpi::read_clpi(&mut SourceReader::new(clip_info)) {
+            Ok(e) => e,
+            Err(e) => bail!("Failed to read clip info: {}", e),
+        };
+        let ep = match clpi.ep_stream(opts.pid) {
+            Ok(e) => e,
+            Err(e) => bail!("{}", e),
+        };
+        let pid = ep.pid;
+        let ep_idx = clpi.ep_streams.iter().position(|e| e.pid == pid).unwrap();
+
+        let coding_type = clpi.program_stream(pid).map(|e| e.coding_type);
+        let codec_id = match coding_type {
+            //no ProgramInfo entry, assume the common case
+            None => ffmpeg_stuff::ffmpeg_sys::AVCodecID::AV_CODEC_ID_H264,
+            Some(e) => match ffmpeg_stuff::codec_for_coding_type(e) {
+                Some(e) => e,
+                None => bail!("Unsupported stream coding type 0x{:02X}", e),
+            },
+        };
+
+        let cached = if opts.cache {
+            index_cache::load(stream.as_ref(), pid, opts.exact)
+        } else {
+            None
+        };
+        let index = match cached {
+            Some(e) => e,
+            None => {
+                let index = build_index(&stream, &clpi, ep, pid, codec_id, opts.exact)?;
+                if opts.cache {
+                    //not being able to cache shouldn't stop anything
+                    let _ = index_cache::store(stream.as_ref(), pid, opts.exact, &index);
+                }
+                index
+            }
+        };
+        let frame_rate = (index.frame_rate_n, index.frame_rate_d);
+        let mut pred = index.pred;
+        let exact = index.exact;
+
+        let leading_frames = pred[0].number;
+        for kf in pred.iter_mut() {
+            kf.number = kf.number.saturating_sub(leading_frames);
+        }
+
+        //a mapping is read directly, the os already caches it
+        let inner: Arc<dyn StreamSource> = if opts.mmap {
+            stream
+        } else {
+            Arc::new(fcache::FileCacheBackend::new(
+                stream,
+                &pred,
+                opts.cache_bytes,
+            ))
+        };
+
+        let analisys = ffmpeg_stuff::Analisys {
+            width: index.width,
+            height: index.height,
+            format: pixfmt::from_raw(index.raw_format),
+            raw_format: index.raw_format,
+            last_packet_frame_cnt: index.last_packet_frame_cnt,
+            last_packet_half_frames: index.last_packet_half_frames,
+            interlaced: index.interlaced,
+        };
+
+        let vs_format = match analisys.format.and_then(pixfmt::vs_format) {
+            Some(e) => e,
+            None => bail!("Unsupported pixel format {}", analisys.raw_format),
+        };
+
+        let clip_frames = pred[pred.len() - 1].number + analisys.last_packet_frame_cnt;
+
+        Ok(Segment {
+            clpi,
+            pid,
+            ep_idx,
+            codec_id,
+            pred,
+            exact,
+            backend: inner,
+            leading_frames,
+            clip_frames,
+            in_frame: 0,
+            num_frames: clip_frames,
+            start: 0,
+            frame_rate_n: frame_rate.0,
+            frame_rate_d: frame_rate.1,
+            analisys,
+            vs_format,
+        })
+    }
+
+    pub fn ep(&self) -> &clpi::EpMapStream {
+        &self.clpi.ep_streams[self.ep_idx]
+    }
+
+    //clip frame number of a 45khz presentation time
+    pub fn pts_to_frame(&self, pts: u32, stc_id: u8) -> u64 {
+        let frame = match &self.exact {
+            Some(e) => e.pts_to_frame(pts, stc_id),
+            None => predict::pts_to_frame(
+                pts,
+                stc_id,
+                &self.ep().combined,
+                &self.clpi.stc_sequences(),
+                (self.frame_rate_n, self.frame_rate_d),
+            ),
+        };
+        frame
+            .saturating_sub(self.leading_frames)
+            .min(self.clip_frames)
+    }
+
+    //45khz presentation time of a clip frame
+    pub fn frame_to_pts(&self, frame: u64) -> u32 {
+        let frame = frame + self.leading_frames;
+        match &self.exact {
+            Some(e) => e.frame_to_pts(frame),
+            None => predict::frame_to_pts(
+                frame,
+                &self.ep().combined,
+                &self.clpi.stc_sequences(),
+                (self.frame_rate_n, self.frame_rate_d),
+            ),
+        }
+    }
+
+    pub fn trim(&mut self, in_time: u32, out_time: u32, stc_id: u8) {
+        let in_frame = self.pts_to_frame(in_time, stc_id);
+        let out_frame = self.pts_to_frame(out_time, stc_id);
+
+        self.in_frame = in_frame;
+        self.num_frames = out_frame.saturating_sub(in_frame);
+    }
+}
+
+//with the repeat flags applied every output frame is two consecutive fields, this is the
+//(output frame numbered) frame each of them comes from. Needs the exact index for the durations
+pub fn rff_frame_map(segments: &[Segment]) -> Vec<(u64, u64)> {
+    let mut fields = Vec::new();
+    for seg in segments {
+        let counts = match &seg.exact {
+            Some(e) => e.field_counts(
+                seg.in_frame + seg.leading_frames,
+                seg.num_frames,
+                predict::clip_frame_rate(&seg.clpi, seg.pid, seg.analisys.interlaced),
+            ),
+            None => vec![2; seg.num_frames as usize],
+        };
+        for (i, cnt) in counts.into_iter().enumerate() {
+            let frame = seg.start + i as u64;
+            fields.extend(std::iter::repeat_n(frame, cnt as usize));
+        }
+    }
+    fields.chunks_exact(2).map(|e| (e[0], e[1])).collect()
+}
+
+//the BDMV folder of a disc, on disk or inside a disc image
+pub enum Bdmv {
+    Dir(PathBuf),
+    Iso(udf::Udf),
+}
+
+impl Bdmv {
+    //`path` is a disc image, the disc root or the BDMV folder itself
+    pub fn open(path: &Path, mmap: bool) -> Result<Bdmv, Error> {
+        if path.is_dir() {
+            let bdmv = path.join("BDMV");
+            if bdmv.is_dir() {
+                return Ok(Bdmv::Dir(bdmv));
+            }
+            return Ok(Bdmv::Dir(path.to_owned()));
+        }
+        let iso = stream_source::open_files(&[path.to_owned()], mmap)?;
+        match udf::Udf::open(iso) {
+            Ok(e) => Ok(Bdmv::Iso(e)),
+            Err(e) => bail!("Failed to read disc image: {}", e),
+        }
+    }
+
+    //`dir` is the folder inside BDMV
+    fn open_file(&self, dir: &str, name: &str, mmap: bool) -> Result<Arc<dyn StreamSource>, Error> {
+        match self {
+            Bdmv::Dir(bdmv) => {
+                let path = bdmv.join(dir).join(name);
+                if !path.exists() {
+                    bail!("{} does not exists", path.display());
+                }
+                Ok(stream_source::open_files(&[path], mmap)?)
+            }
+            Bdmv::Iso(udf) => match udf.open_file(&format!("BDMV/{}/{}", dir, name)) {
+                Ok(e) => Ok(e),
+                Err(e) => bail!("{}", e),
+            },
+        }
+    }
+
+    pub fn open_clip_stream(
+        &self,
+        clip_name: &str,
+        mmap: bool,
+    ) -> Result<Arc<dyn StreamSource>, Error> {
+        self.open_file("STREAM", &format!("{}.m2ts", clip_name), mmap)
+    }
+
+    fn open_clip(&self, clip_name: &str, opts: &OpenOptions) -> Result<Segment, Error> {
+        let stream = self.open_clip_stream(clip_name, opts.mmap)?;
+        let clip_info = self.open_file("CLIPINF", &format!("{}.clpi", clip_name), false)?;
+        Segment::open(stream, clip_info, opts)
+    }
+}
+
+//BDMV/STREAM/00000.m2ts -> BDMV/CLIPINF/00000.clpi
+pub fn clip_info_for_stream(stream_path: &Path) -> Result<PathBuf, Error> {
+    let stream_idx_number = stream_path.file_stem().and_then(|e| e.to_str());
+    let bdmv = stream_path.parent().and_then(|e| e.parent());
+    match (stream_idx_number, bdmv) {
+        (Some(number), Some(bdmv)) => Ok(bdmv.join("CLIPINF").join(format!("{}.clpi", number))),
+        _ => bail!("{} is not in a BDMV/STREAM folder", stream_path.display()),
+    }
+}
+
+//the clip info is the one of the first part
+pub fn open_stream(stream_paths: &[PathBuf], opts: &OpenOptions) -> Result<Vec<Segment>, Error> {
+    let clip_info = clip_info_for_stream(&stream_paths[0])?;
+    if !clip_info.exists() {
+        bail!("CLIPINFO does not exists");
+    }
+
+    let stream = stream_source::open_files(stream_paths, opts.mmap)?;
+    let clip_info: Arc<dyn StreamSource> = Arc::new(FileSource::open(&clip_info)?);
+    Ok(vec![Segment::open(stream, clip_info, opts)?])
+}
+
+pub fn open_playlist(
+    bdmv: &Bdmv,
+    playlist: Arc<dyn StreamSource>,
+    opts: &OpenOptions,
+) -> Result<Vec<Segment>, Error> {
+    let mpls = match mpls::read_mpls(&mut SourceReader::new(playlist)) {
+        Ok(e) => e,
+        Err(e) => bail!("Failed to read playlist: {}", e),
+    };
+
+    let mut segments: Vec<Segment> = Vec::with_capacity(mpls.play_items.len());
+    let mut start = 0;
+    for item in &mpls.play_items {
+        let mut seg = bdmv.open_clip(&item.clip_name, opts)?;
+        seg.trim(item.in_time, item.out_time, item.stc_id);
+        if seg.num_frames == 0 {
+            continue;
+        }
+
+        if let Some(first) = segments.first() {
+            if first.analisys.width != seg.analisys.width
+                || first.analisys.height != seg.analisys.height
+            {
+                bail!("Clip {} has a different resolution", item.clip_name);
+            }
+            if first.vs_format != seg.vs_format {
+                bail!("Clip {} has a different pixel format", item.clip_name);
+            }
+        }
+
+        seg.start = start;
+        start += seg.num_frames;
+        segments.push(seg);
+    }
+    if segments.is_empty() {
+        bail!("Playlist has no frames");
+    }
+    Ok(segments)
+}
+
+//a playlist, a single m2ts or the parts of a split one
+pub fn open_path(paths: &[PathBuf], opts: &OpenOptions) -> Result<Vec<Segment>, Error> {
+    if let Some(path) = paths.iter().find(|e| !e.exists()) {
+        bail!("{} does not exists", path.display());
+    }
+    let path = &paths[0];
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) if e.eq_ignore_ascii_case("mpls") => {
+            if paths.len() != 1 {
+                bail!("A playlist can't be split into parts");
+            }
+            let bdmv = match path.parent().and_then(|e| e.parent()) {
+                Some(e) => Bdmv::Dir(e.to_owned()),
+                None => bail!("{} is not in a BDMV/PLAYLIST folder", path.display()),
+            };
+            open_playlist(&bdmv, Arc::new(FileSource::open(path)?), opts)
+        }
+        _ => open_stream(paths, opts),
+    }
+}
+
+//a clip or playlist (by number, "00000") of a disc image or disc folder
+pub fn open_disc(
+    path: &Path,
+    clip: Option<&str>,
+    playlist: Option<&str>,
+    opts: &OpenOptions,
+) -> Result<Vec<Segment>, Error> {
+    if !path.exists() {
+        bail!("Disc does not exists");
+    }
+    let bdmv = Bdmv::open(path, opts.mmap)?;
+    match (clip, playlist) {
+        (Some(clip), None) => Ok(vec![bdmv.open_clip(clip, opts)?]),
+        (None, Some(playlist)) => {
+            let mpls = bdmv.open_file("PLAYLIST", &format!("{}.mpls", playlist), false)?;
+            open_playlist(&bdmv, mpls, opts)
+        }
+        _ => bail!("Either clip or playlist has to be given"),
+    }
+}
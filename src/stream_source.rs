@@ -0,0 +1,349 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use memmap2::Mmap;
+
+use crate::error::{Error, Result};
+
+//random access to the bytes of a m2ts/clpi/mpls, wherever they are stored
+pub trait StreamSource: Send + Sync {
+    //reads from `pos` into `b`, returns how much was read, 0 at the end
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize>;
+    fn size(&self) -> u64;
+    //identifies the data for the index cache, changes when the data does
+    fn id(&self) -> String;
+}
+
+pub fn read_exact_at(src: &dyn StreamSource, mut pos: u64, mut b: &mut [u8]) -> Result<()> {
+    while !b.is_empty() {
+        let r = src.read_at(pos, b)?;
+        if r == 0 {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        pos += r as u64;
+        b = &mut b[r..];
+    }
+    Ok(())
+}
+
+fn file_id(path: &Path, f: &File) -> io::Result<String> {
+    let meta = f.metadata()?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_nanos())
+        .unwrap_or(0);
+    let path = fs::canonicalize(path)?;
+    Ok(format!("{}|{}|{}", path.display(), meta.len(), mtime))
+}
+
+pub struct FileSource {
+    f: Mutex<File>,
+    size: u64,
+    id: String,
+}
+
+impl FileSource {
+    pub fn open(path: &Path) -> Result<FileSource> {
+        let f = File::open(path)?;
+        Ok(FileSource {
+            size: f.metadata()?.len(),
+            id: file_id(path, &f)?,
+            f: Mutex::new(f),
+        })
+    }
+}
+
+impl StreamSource for FileSource {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        let mut f = self.f.lock().unwrap();
+        f.seek(SeekFrom::Start(pos))?;
+        Ok(f.read(b)?)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+//read only mapping of the whole file, the os does the caching
+pub struct MmapSource {
+    map: Mmap,
+    id: String,
+}
+
+impl MmapSource {
+    pub fn open(path: &Path) -> Result<MmapSource> {
+        let f = File::open(path)?;
+        //the file is opened read only and blu-ray files don't get modified while they are read
+        let map = unsafe { Mmap::map(&f)? };
+        Ok(MmapSource {
+            map,
+            id: file_id(path, &f)?,
+        })
+    }
+}
+
+impl StreamSource for MmapSource {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        if pos >= self.map.len() as u64 {
+            return Ok(0);
+        }
+        let src = &self.map[pos as usize..];
+        let to_read = b.len().min(src.len());
+        b[..to_read].copy_from_slice(&src[..to_read]);
+        Ok(to_read)
+    }
+
+    fn size(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+//parts played back to back, e.g. a m2ts split into several files
+pub struct ConcatSource {
+    //with the offset each part starts at
+    parts: Vec<(u64, Arc<dyn StreamSource>)>,
+    size: u64,
+}
+
+impl ConcatSource {
+    pub fn new(parts: Vec<Arc<dyn StreamSource>>) -> ConcatSource {
+        let mut size = 0;
+        let parts = parts
+            .into_iter()
+            .map(|e| {
+                let start = size;
+                size += e.size();
+                (start, e)
+            })
+            .collect();
+        ConcatSource { parts, size }
+    }
+}
+
+impl StreamSource for ConcatSource {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        for (start, part) in &self.parts {
+            if pos >= *start && pos < start + part.size() {
+                //never across a part boundary, the caller reads again for the rest
+                return part.read_at(pos - start, b);
+            }
+        }
+        Ok(0)
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn id(&self) -> String {
+        self.parts
+            .iter()
+            .map(|e| e.1.id())
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+}
+
+//`len` bytes at `start` of another source, e.g. a file inside a disc image
+pub struct RangeSource {
+    inner: Arc<dyn StreamSource>,
+    start: u64,
+    len: u64,
+}
+
+impl RangeSource {
+    pub fn new(inner: Arc<dyn StreamSource>, start: u64, len: u64) -> RangeSource {
+        RangeSource { inner, start, len }
+    }
+}
+
+impl StreamSource for RangeSource {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        if pos >= self.len {
+            return Ok(0);
+        }
+        let to_read = (b.len() as u64).min(self.len - pos) as usize;
+        self.inner.read_at(self.start + pos, &mut b[..to_read])
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn id(&self) -> String {
+        format!("{}@{}+{}", self.inner.id(), self.start, self.len)
+    }
+}
+
+//Read + Seek over a source, for the clpi/mpls parsers and the pes scan
+pub struct SourceReader {
+    src: Arc<dyn StreamSource>,
+    pos: u64,
+}
+
+impl SourceReader {
+    pub fn new(src: Arc<dyn StreamSource>) -> SourceReader {
+        SourceReader { src, pos: 0 }
+    }
+}
+
+impl Read for SourceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let r = self.src.read_at(self.pos, buf)?;
+        self.pos += r as u64;
+        Ok(r)
+    }
+}
+
+impl Seek for SourceReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(e) => e as i64,
+            SeekFrom::End(e) => self.src.size() as i64 + e,
+            SeekFrom::Current(e) => self.pos as i64 + e,
+        };
+        if new < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start",
+            ));
+        }
+        self.pos = new as u64;
+        Ok(self.pos)
+    }
+}
+
+//a single file, or the parts of a split one joined
+pub fn open_files(paths: &[PathBuf], mmap: bool) -> Result<Arc<dyn StreamSource>> {
+    let mut parts: Vec<Arc<dyn StreamSource>> = Vec::with_capacity(paths.len());
+    for path in paths {
+        if mmap {
+            parts.push(Arc::new(MmapSource::open(path)?));
+        } else {
+            parts.push(Arc::new(FileSource::open(path)?));
+        }
+    }
+    if parts.len() == 1 {
+        Ok(parts.pop().unwrap())
+    } else {
+        Ok(Arc::new(ConcatSource::new(parts)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Bytes(Vec<u8>);
+
+    impl StreamSource for Bytes {
+        fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+            let src = self.0.get(pos as usize..).unwrap_or(&[]);
+            let to_read = b.len().min(src.len());
+            b[..to_read].copy_from_slice(&src[..to_read]);
+            Ok(to_read)
+        }
+
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn id(&self) -> String {
+            format!("bytes{}", self.0.len())
+        }
+    }
+
+    fn concat() -> ConcatSource {
+        ConcatSource::new(vec![
+            Arc::new(Bytes(vec![0, 1, 2])),
+            Arc::new(Bytes(vec![3, 4, 5, 6, 7])),
+        ])
+    }
+
+    #[test]
+    fn concat_parts() {
+        let src = concat();
+        assert_eq!(src.size(), 8);
+        assert_eq!(src.id(), "bytes3+bytes5");
+
+        let mut buf = [0u8; 4];
+        //stops at the end of the first part
+        assert_eq!(src.read_at(1, &mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [1, 2]);
+        read_exact_at(&src, 1, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        assert_eq!(src.read_at(8, &mut buf).unwrap(), 0);
+        assert!(read_exact_at(&src, 6, &mut buf).is_err());
+    }
+
+    #[test]
+    fn range_of_a_source() {
+        let src = RangeSource::new(Arc::new(concat()), 2, 4);
+        assert_eq!(src.size(), 4);
+        assert_eq!(src.id(), "bytes3+bytes5@2+4");
+
+        let mut buf = [0u8; 8];
+        read_exact_at(&src, 0, &mut buf[..4]).unwrap();
+        assert_eq!(buf[..4], [2, 3, 4, 5]);
+        assert_eq!(src.read_at(3, &mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 5);
+        assert_eq!(src.read_at(4, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn reader_seeks() {
+        let mut r = SourceReader::new(Arc::new(concat()));
+        assert_eq!(r.seek(SeekFrom::End(-2)).unwrap(), 6);
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, [6, 7]);
+
+        r.seek(SeekFrom::Start(1)).unwrap();
+        assert_eq!(r.seek(SeekFrom::Current(1)).unwrap(), 2);
+        let mut buf = [0u8; 3];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2, 3, 4]);
+
+        assert!(r.seek(SeekFrom::Current(-10)).is_err());
+    }
+
+    #[test]
+    fn files_and_maps() {
+        let dir = std::env::temp_dir().join(format!("bdngsp-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths = [dir.join("00001.m2ts"), dir.join("00002.m2ts")];
+        fs::write(&paths[0], [0u8, 1, 2]).unwrap();
+        fs::write(&paths[1], [3u8, 4, 5, 6, 7]).unwrap();
+
+        for mmap in [false, true] {
+            let single = open_files(&paths[1..], mmap).unwrap();
+            assert_eq!(single.size(), 5);
+            assert!(single.id().contains("00002.m2ts|5|"));
+
+            let joined = open_files(&paths, mmap).unwrap();
+            let mut buf = [0u8; 8];
+            read_exact_at(joined.as_ref(), 0, &mut buf).unwrap();
+            assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
+            assert_eq!(joined.read_at(8, &mut buf).unwrap(), 0);
+        }
+
+        assert!(open_files(&[dir.join("missing.m2ts")], false).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
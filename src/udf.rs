@@ -0,0 +1,595 @@
+use std::{collections::HashMap, sync::Arc};
+
+use byteorder::{ByteOrder, LE};
+
+use crate::error::{Error, Result};
+use crate::stream_source::{read_exact_at, ConcatSource, RangeSource, StreamSource};
+
+//read only UDF 2.50 (what blu-rays use) with physical and metadata partitions
+
+const SECTOR_SIZE: u64 = 2048;
+const ANCHOR_SECTOR: u64 = 256;
+
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_ALLOCATION_EXTENT: u16 = 258;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+
+macro_rules! udf_error {
+    ($($arg:tt)*) => {
+        Error::Udf(format!($($arg)*))
+    };
+}
+
+//descriptor tag, the checksum covers the 16 tag bytes without itself
+fn check_tag(b: &[u8], expected: &[u16]) -> Result<u16> {
+    if b.len() < 16 {
+        return Err(udf_error!("Descriptor too short"));
+    }
+    let sum = b[..16]
+        .iter()
+        .enumerate()
+        .filter(|e| e.0 != 4)
+        .fold(0u8, |acc, e| acc.wrapping_add(*e.1));
+    let id = LE::read_u16(&b[0..2]);
+    if sum != b[4] || !expected.contains(&id) {
+        return Err(udf_error!(
+            "Expected descriptor {:?}, found {}",
+            expected,
+            id
+        ));
+    }
+    Ok(id)
+}
+
+fn read_block(src: &dyn StreamSource, lb: u32, size: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; size as usize];
+    read_exact_at(src, lb as u64 * size, &mut buf)?;
+    Ok(buf)
+}
+
+//d-string/file identifier, first byte is the compression id
+fn decode_name(b: &[u8]) -> String {
+    match b.first() {
+        Some(8) => b[1..].iter().map(|e| *e as char).collect(),
+        Some(16) => {
+            let units = b[1..]
+                .chunks_exact(2)
+                .map(|e| u16::from_be_bytes([e[0], e[1]]));
+            char::decode_utf16(units)
+                .map(|e| e.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect()
+        }
+        _ => String::new(),
+    }
+}
+
+//unrecorded extents read as zeros
+struct ZeroSource {
+    len: u64,
+}
+
+impl StreamSource for ZeroSource {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        if pos >= self.len {
+            return Ok(0);
+        }
+        let to_read = (b.len() as u64).min(self.len - pos) as usize;
+        b[..to_read].fill(0);
+        Ok(to_read)
+    }
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn id(&self) -> String {
+        format!("zero+{}", self.len)
+    }
+}
+
+//data embedded in the file entry
+struct MemSource {
+    data: Vec<u8>,
+}
+
+impl StreamSource for MemSource {
+    fn read_at(&self, pos: u64, b: &mut [u8]) -> Result<usize> {
+        if pos >= self.data.len() as u64 {
+            return Ok(0);
+        }
+        let src = &self.data[pos as usize..];
+        let to_read = b.len().min(src.len());
+        b[..to_read].copy_from_slice(&src[..to_read]);
+        Ok(to_read)
+    }
+
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn id(&self) -> String {
+        format!("mem+{}", hex(&self.data))
+    }
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|e| format!("{:02x}", e)).collect()
+}
+
+struct FileEntry {
+    file_type: u8,
+    data: Arc<dyn StreamSource>,
+}
+
+struct DirEntry {
+    name: String,
+    //partition reference and block of the file entry
+    icb: (u16, u32),
+}
+
+pub struct Udf {
+    block_size: u64,
+    //by partition reference number (the index in the logical volume's partition maps)
+    partitions: Vec<Arc<dyn StreamSource>>,
+    //partition reference and block of the root directory's file entry
+    root: (u16, u32),
+}
+
+impl Udf {
+    pub fn open(iso: Arc<dyn StreamSource>) -> Result<Udf> {
+        let mut anchor = vec![0u8; SECTOR_SIZE as usize];
+        read_exact_at(iso.as_ref(), ANCHOR_SECTOR * SECTOR_SIZE, &mut anchor)?;
+        if check_tag(&anchor, &[2]).is_err() {
+            return Err(udf_error!(
+                "No UDF anchor volume descriptor, not a disc image"
+            ));
+        }
+        let vds_length = LE::read_u32(&anchor[16..20]) as u64;
+        let vds_location = LE::read_u32(&anchor[20..24]) as u64;
+
+        //partition number -> (start sector, length in sectors)
+        let mut partition_descs: HashMap<u16, (u64, u64)> = HashMap::new();
+        let mut lvd: Option<Vec<u8>> = None;
+
+        for i in 0..vds_length / SECTOR_SIZE {
+            let mut d = vec![0u8; SECTOR_SIZE as usize];
+            read_exact_at(iso.as_ref(), (vds_location + i) * SECTOR_SIZE, &mut d)?;
+            let id = match check_tag(&d, &[1, 3, 4, 5, 6, 7, 8, 9]) {
+                Ok(e) => e,
+                Err(_) => break,
+            };
+            match id {
+                TAG_PARTITION => {
+                    let number = LE::read_u16(&d[22..24]);
+                    let start = LE::read_u32(&d[188..192]) as u64;
+                    let length = LE::read_u32(&d[192..196]) as u64;
+                    partition_descs.insert(number, (start, length));
+                }
+                TAG_LOGICAL_VOLUME => lvd = Some(d),
+                TAG_TERMINATING => break,
+                _ => {}
+            }
+        }
+        let lvd = match lvd {
+            Some(e) => e,
+            None => return Err(udf_error!("No logical volume descriptor")),
+        };
+
+        let block_size = LE::read_u32(&lvd[212..216]) as u64;
+        if !block_size.is_power_of_two() || !(512..=4096).contains(&block_size) {
+            return Err(udf_error!("Invalid logical block size {}", block_size));
+        }
+        let map_table_length = LE::read_u32(&lvd[264..268]) as usize;
+        let num_maps = LE::read_u32(&lvd[268..272]);
+        let maps = &lvd[440..(440 + map_table_length).min(lvd.len())];
+
+        let physical = |number: u16| -> Result<Arc<dyn StreamSource>> {
+            match partition_descs.get(&number) {
+                Some((start, length)) => Ok(Arc::new(RangeSource::new(
+                    iso.clone(),
+                    start * SECTOR_SIZE,
+                    length * SECTOR_SIZE,
+                ))),
+                None => Err(udf_error!(
+                    "No partition descriptor for partition {}",
+                    number
+                )),
+            }
+        };
+
+        let mut partitions: Vec<Arc<dyn StreamSource>> = Vec::with_capacity(num_maps as usize);
+        let mut pos = 0;
+        for _ in 0..num_maps {
+            if pos + 2 > maps.len() {
+                return Err(udf_error!("Partition map table too short"));
+            }
+            let map_type = maps[pos];
+            let map_length = maps[pos + 1] as usize;
+            let expected_length = if map_type == 1 { 6 } else { 64 };
+            if map_length < expected_length || pos + map_length > maps.len() {
+                return Err(udf_error!("Partition map {} too short", partitions.len()));
+            }
+            let map = &maps[pos..pos + map_length];
+            pos += map_length;
+
+            match map_type {
+                1 => partitions.push(physical(LE::read_u16(&map[4..6]))?),
+                2 => {
+                    let identifier = &map[5..28];
+                    let number = LE::read_u16(&map[38..40]);
+                    if identifier.starts_with(b"*UDF Metadata Partition") {
+                        let part = physical(number)?;
+                        let main = LE::read_u32(&map[40..44]);
+                        let mirror = LE::read_u32(&map[44..48]);
+                        //the mirror only matters if the main copy is damaged
+                        let metadata = read_file_entry(&part, main, block_size, &[])
+                            .or_else(|_| read_file_entry(&part, mirror, block_size, &[]))?;
+                        partitions.push(metadata.data);
+                    } else if identifier.starts_with(b"*UDF Sparable Partition") {
+                        //sparing only remaps defective blocks of rewritable media
+                        partitions.push(physical(number)?);
+                    } else {
+                        return Err(udf_error!(
+                            "Unsupported partition map {}",
+                            String::from_utf8_lossy(identifier)
+                        ));
+                    }
+                }
+                e => return Err(udf_error!("Unknown partition map type {}", e)),
+            }
+        }
+
+        //the file set descriptor is where the logical volume contents use points
+        let fsd_lb = LE::read_u32(&lvd[252..256]);
+        let fsd_part = LE::read_u16(&lvd[256..258]);
+        let fsd = match partitions.get(fsd_part as usize) {
+            Some(e) => read_block(e.as_ref(), fsd_lb, block_size)?,
+            None => return Err(udf_error!("File set descriptor in unknown partition")),
+        };
+        check_tag(&fsd, &[TAG_FILE_SET])?;
+        let root = (LE::read_u16(&fsd[408..410]), LE::read_u32(&fsd[404..408]));
+
+        Ok(Udf {
+            block_size,
+            partitions,
+            root,
+        })
+    }
+
+    fn entry(&self, icb: (u16, u32)) -> Result<FileEntry> {
+        match self.partitions.get(icb.0 as usize) {
+            Some(e) => read_file_entry(e, icb.1, self.block_size, &self.partitions),
+            None => Err(udf_error!("File entry in unknown partition {}", icb.0)),
+        }
+    }
+
+    fn read_dir(&self, dir: &FileEntry) -> Result<Vec<DirEntry>> {
+        let mut buf = vec![0u8; dir.data.size() as usize];
+        read_exact_at(dir.data.as_ref(), 0, &mut buf)?;
+
+        let mut ret = Vec::new();
+        let mut pos = 0;
+        while pos + 38 <= buf.len() {
+            let fid = &buf[pos..];
+            check_tag(fid, &[TAG_FILE_IDENTIFIER])?;
+            let characteristics = fid[18];
+            let l_fi = fid[19] as usize;
+            let icb = (LE::read_u16(&fid[28..30]), LE::read_u32(&fid[24..28]));
+            let l_iu = LE::read_u16(&fid[36..38]) as usize;
+            let len = (38 + l_iu + l_fi + 3) & !3;
+            if pos + 38 + l_iu + l_fi > buf.len() {
+                return Err(udf_error!("File identifier descriptor too long"));
+            }
+
+            //skip deleted entries and the parent
+            if characteristics & 0x0C == 0 {
+                let name = decode_name(&fid[38 + l_iu..38 + l_iu + l_fi]);
+                ret.push(DirEntry { name, icb });
+            }
+            pos += len;
+        }
+        Ok(ret)
+    }
+
+    //`path` relative to the root of the disc, "BDMV/STREAM/00000.m2ts"
+    pub fn open_file(&self, path: &str) -> Result<Arc<dyn StreamSource>> {
+        let mut entry = self.entry(self.root)?;
+        for component in path.split('/').filter(|e| !e.is_empty()) {
+            if entry.file_type != FILE_TYPE_DIRECTORY {
+                return Err(udf_error!("{} is not a directory", component));
+            }
+            let found = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|e| e.name.eq_ignore_ascii_case(component));
+            entry = match found {
+                Some(e) => self.entry(e.icb)?,
+                None => return Err(udf_error!("{} not found in the disc image", path)),
+            };
+        }
+        if entry.file_type == FILE_TYPE_DIRECTORY {
+            return Err(udf_error!("{} is a directory", path));
+        }
+        Ok(entry.data)
+    }
+}
+
+//file entry at block `lb` of `part`, short allocation descriptors are relative to `part`,
+//long ones to the partition maps
+fn read_file_entry(
+    part: &Arc<dyn StreamSource>,
+    lb: u32,
+    block_size: u64,
+    partitions: &[Arc<dyn StreamSource>],
+) -> Result<FileEntry> {
+    let fe = read_block(part.as_ref(), lb, block_size)?;
+    let tag = check_tag(&fe, &[TAG_FILE_ENTRY, TAG_EXTENDED_FILE_ENTRY])?;
+
+    let file_type = fe[27];
+    let ad_type = LE::read_u16(&fe[34..36]) & 0x07;
+    let info_length = LE::read_u64(&fe[56..64]);
+    let (l_ea, l_ad, base) = if tag == TAG_FILE_ENTRY {
+        (
+            LE::read_u32(&fe[168..172]),
+            LE::read_u32(&fe[172..176]),
+            176,
+        )
+    } else {
+        (
+            LE::read_u32(&fe[208..212]),
+            LE::read_u32(&fe[212..216]),
+            216,
+        )
+    };
+    let start = base + l_ea as usize;
+    let end = start + l_ad as usize;
+    if end > fe.len() {
+        return Err(udf_error!("Allocation descriptors past the file entry"));
+    }
+
+    if ad_type == 3 {
+        let mut data = fe[start..end].to_vec();
+        data.truncate(info_length as usize);
+        return Ok(FileEntry {
+            file_type,
+            data: Arc::new(MemSource { data }),
+        });
+    }
+
+    let mut extents: Vec<Arc<dyn StreamSource>> = Vec::new();
+    let mut ads = fe[start..end].to_vec();
+    loop {
+        let mut next = None;
+        let ad_len = match ad_type {
+            0 => 8,
+            1 => 16,
+            e => return Err(udf_error!("Unsupported allocation descriptor type {}", e)),
+        };
+        for ad in ads.chunks_exact(ad_len) {
+            let length = LE::read_u32(&ad[0..4]);
+            let extent_type = length >> 30;
+            let length = (length & 0x3FFF_FFFF) as u64;
+            if length == 0 {
+                break;
+            }
+            let location = LE::read_u32(&ad[4..8]);
+            let extent_part = if ad_type == 0 {
+                part
+            } else {
+                match partitions.get(LE::read_u16(&ad[8..10]) as usize) {
+                    Some(e) => e,
+                    None => return Err(udf_error!("Extent in unknown partition")),
+                }
+            };
+            match extent_type {
+                0 => extents.push(Arc::new(RangeSource::new(
+                    extent_part.clone(),
+                    location as u64 * block_size,
+                    length,
+                ))),
+                1 | 2 => extents.push(Arc::new(ZeroSource { len: length })),
+                //continues in an allocation extent descriptor
+                _ => next = Some((extent_part.clone(), location)),
+            }
+        }
+
+        match next {
+            Some((next_part, location)) => {
+                let aed = read_block(next_part.as_ref(), location, block_size)?;
+                check_tag(&aed, &[TAG_ALLOCATION_EXTENT])?;
+                let l_ad = LE::read_u32(&aed[20..24]) as usize;
+                if 24 + l_ad > aed.len() {
+                    return Err(udf_error!("Allocation extent descriptor too long"));
+                }
+                ads = aed[24..24 + l_ad].to_vec();
+            }
+            None => break,
+        }
+    }
+
+    let data = Arc::new(ConcatSource::new(extents));
+    let len = info_length.min(data.size());
+    Ok(FileEntry {
+        file_type,
+        data: Arc::new(RangeSource::new(data, 0, len)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::ByteOrder;
+
+    use super::*;
+
+    const BLOCK: usize = SECTOR_SIZE as usize;
+    const PARTITION_START: usize = 272;
+
+    fn tag(d: &mut [u8], id: u16) {
+        LE::write_u16(&mut d[0..2], id);
+        d[4] = d[..16]
+            .iter()
+            .enumerate()
+            .filter(|e| e.0 != 4)
+            .fold(0u8, |acc, e| acc.wrapping_add(*e.1));
+    }
+
+    fn fid(name: &str, lb: u32, directory: bool) -> Vec<u8> {
+        let mut d = vec![0u8; 38];
+        d[18] = if directory { 0x02 } else { 0x00 };
+        d[19] = 1 + name.len() as u8;
+        LE::write_u32(&mut d[24..28], lb);
+        d.push(8);
+        d.extend(name.bytes());
+        d.resize((d.len() + 3) & !3, 0);
+        tag(&mut d, TAG_FILE_IDENTIFIER);
+        d
+    }
+
+    //file entry with the allocation descriptors or the embedded data in `ads`
+    fn file_entry(file_type: u8, ad_type: u16, info_length: u64, ads: &[u8]) -> Vec<u8> {
+        let mut d = vec![0u8; BLOCK];
+        d[27] = file_type;
+        LE::write_u16(&mut d[34..36], ad_type);
+        LE::write_u64(&mut d[56..64], info_length);
+        LE::write_u32(&mut d[172..176], ads.len() as u32);
+        d[176..176 + ads.len()].copy_from_slice(ads);
+        tag(&mut d, TAG_FILE_ENTRY);
+        d
+    }
+
+    fn directory(entries: &[Vec<u8>]) -> Vec<u8> {
+        let fids = entries.concat();
+        file_entry(FILE_TYPE_DIRECTORY, 3, fids.len() as u64, &fids)
+    }
+
+    fn short_ad(length: u32, extent_type: u32, lb: u32) -> Vec<u8> {
+        let mut d = vec![0u8; 8];
+        LE::write_u32(&mut d[0..4], length | (extent_type << 30));
+        LE::write_u32(&mut d[4..8], lb);
+        d
+    }
+
+    //BDMV/STREAM/00000.M2TS with a recorded, an unrecorded and another recorded extent
+    //and BDMV/index.bdmv embedded in its file entry
+    fn image() -> Vec<u8> {
+        let mut img = vec![0u8; (PARTITION_START + 16) * BLOCK];
+        let mut put = |sector: usize, d: &[u8]| {
+            img[sector * BLOCK..sector * BLOCK + d.len()].copy_from_slice(d);
+        };
+
+        let mut anchor = vec![0u8; BLOCK];
+        LE::write_u32(&mut anchor[16..20], 3 * BLOCK as u32);
+        LE::write_u32(&mut anchor[20..24], 32);
+        tag(&mut anchor, 2);
+        put(ANCHOR_SECTOR as usize, &anchor);
+
+        let mut pd = vec![0u8; BLOCK];
+        LE::write_u32(&mut pd[188..192], PARTITION_START as u32);
+        LE::write_u32(&mut pd[192..196], 16);
+        tag(&mut pd, TAG_PARTITION);
+        put(32, &pd);
+
+        let mut lvd = vec![0u8; BLOCK];
+        LE::write_u32(&mut lvd[212..216], BLOCK as u32);
+        LE::write_u32(&mut lvd[264..268], 6);
+        LE::write_u32(&mut lvd[268..272], 1);
+        lvd[440..446].copy_from_slice(&[1, 6, 1, 0, 0, 0]);
+        tag(&mut lvd, TAG_LOGICAL_VOLUME);
+        put(33, &lvd);
+
+        let mut td = vec![0u8; BLOCK];
+        tag(&mut td, TAG_TERMINATING);
+        put(34, &td);
+
+        let part = |lb: usize| PARTITION_START + lb;
+        let mut fsd = vec![0u8; BLOCK];
+        LE::write_u32(&mut fsd[404..408], 1);
+        tag(&mut fsd, TAG_FILE_SET);
+        put(part(0), &fsd);
+
+        put(part(1), &directory(&[fid("BDMV", 2, true)]));
+        put(
+            part(2),
+            &directory(&[fid("STREAM", 3, true), fid("index.bdmv", 5, false)]),
+        );
+        put(part(3), &directory(&[fid("00000.M2TS", 4, false)]));
+
+        let ads = [
+            short_ad(BLOCK as u32, 0, 10),
+            short_ad(BLOCK as u32, 1, 0),
+            short_ad(100, 0, 12),
+        ]
+        .concat();
+        put(part(4), &file_entry(5, 0, 2 * BLOCK as u64 + 100, &ads));
+        put(part(5), &file_entry(5, 3, 4, b"INDX"));
+
+        put(part(10), &[0xAA; BLOCK]);
+        put(part(12), &[0xBB; BLOCK]);
+        img
+    }
+
+    fn open() -> Udf {
+        Udf::open(Arc::new(MemSource { data: image() })).unwrap()
+    }
+
+    fn read_all(src: &dyn StreamSource) -> Vec<u8> {
+        let mut buf = vec![0u8; src.size() as usize];
+        read_exact_at(src, 0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_file_extents() {
+        let f = open().open_file("BDMV/STREAM/00000.m2ts").unwrap();
+        let data = read_all(f.as_ref());
+        assert_eq!(data.len(), 2 * BLOCK + 100);
+        assert!(data[..BLOCK].iter().all(|e| *e == 0xAA));
+        assert!(data[BLOCK..2 * BLOCK].iter().all(|e| *e == 0));
+        assert!(data[2 * BLOCK..].iter().all(|e| *e == 0xBB));
+    }
+
+    #[test]
+    fn reads_embedded_file() {
+        let f = open().open_file("/BDMV/index.bdmv").unwrap();
+        assert_eq!(read_all(f.as_ref()), b"INDX");
+    }
+
+    #[test]
+    fn rejects_missing_files_and_directories() {
+        let udf = open();
+        assert!(matches!(
+            udf.open_file("BDMV/STREAM/00001.m2ts"),
+            Err(Error::Udf(_))
+        ));
+        assert!(matches!(udf.open_file("BDMV/STREAM"), Err(Error::Udf(_))));
+        assert!(matches!(
+            udf.open_file("BDMV/index.bdmv/x"),
+            Err(Error::Udf(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_block_sizes() {
+        for block_size in [0u32, 1000, 8192] {
+            let mut img = image();
+            LE::write_u32(&mut img[33 * BLOCK + 212..33 * BLOCK + 216], block_size);
+            let src = Arc::new(MemSource { data: img });
+            assert!(matches!(Udf::open(src), Err(Error::Udf(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_non_udf_images() {
+        let src = Arc::new(MemSource {
+            data: vec![0u8; (ANCHOR_SECTOR as usize + 1) * BLOCK],
+        });
+        assert!(matches!(Udf::open(src), Err(Error::Udf(_))));
+    }
+}